@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use teloxide::types::ChatId;
+
+use crate::ProtectedSharedState;
+
+pub const SNOOZE_DURATION: Duration = Duration::from_secs(600);
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub type AlertId = u64;
+
+/// Message sent again every repeat interval until it is acknowledged from the inline keyboard
+pub struct Alert {
+    pub message: String,
+    pub chat_ids: Vec<ChatId>,
+    repeat_interval: Duration,
+    next_repeat: Instant,
+
+    /// Messages carrying the inline keyboard, which is removed once the alert is acknowledged
+    pub sent_messages: Vec<(ChatId, i32)>
+}
+
+/// Alerts which have not been acknowledged yet, they are lost when the bot is restarted
+#[derive(Default)]
+pub struct Alerts {
+    next_id: AlertId,
+    pending: HashMap<AlertId, Alert>
+}
+
+impl Alerts {
+
+    pub fn add(&mut self, message: String, chat_ids: Vec<ChatId>, repeat_interval: Duration) -> AlertId {
+        let alert_id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(alert_id, Alert {
+            message,
+            chat_ids,
+            repeat_interval,
+            next_repeat: Instant::now() + repeat_interval,
+            sent_messages: vec![]
+        });
+        alert_id
+    }
+
+    pub fn get_mut(&mut self, alert_id: AlertId) -> Option<&mut Alert> {
+        self.pending.get_mut(&alert_id)
+    }
+
+    pub fn is_pending(&self, alert_id: AlertId) -> bool {
+        self.pending.contains_key(&alert_id)
+    }
+
+    pub fn acknowledge(&mut self, alert_id: AlertId) -> Option<Alert> {
+        self.pending.remove(&alert_id)
+    }
+
+    pub fn snooze(&mut self, alert_id: AlertId) -> Option<&Alert> {
+        let alert = self.pending.get_mut(&alert_id)?;
+        alert.next_repeat = Instant::now() + SNOOZE_DURATION;
+        Some(alert)
+    }
+
+    // returns: the alerts to send again, their next repeat is scheduled
+    fn due(&mut self) -> Vec<(AlertId, String, Vec<ChatId>)> {
+        let now = Instant::now();
+        self.pending.iter_mut().filter(|(_, alert)| alert.next_repeat <= now).map(|(alert_id, alert)| {
+            alert.next_repeat = now + alert.repeat_interval;
+            (*alert_id, alert.message.clone(), alert.chat_ids.clone())
+        }).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+}
+
+/// Sends a message with the acknowledgement keyboard, repeated until it is acknowledged
+pub async fn raise(shared_state: &ProtectedSharedState, message: String, chat_ids: Vec<ChatId>, repeat_interval: Duration) {
+    let mut locked_shared_state = shared_state.lock().await;
+    let alert_id = locked_shared_state.alerts.add(message.clone(), chat_ids.clone(), repeat_interval);
+    log::info!("alert {} raised: {}", alert_id, message);
+    locked_shared_state.outbox.push(&chat_ids, &message, Some(alert_id));
+}
+
+/// Sends a state message, as an alert when it has a repeat interval
+pub async fn notify(shared_state: &ProtectedSharedState, message: String, chat_ids: Vec<ChatId>, repeat_interval: Option<u64>) {
+    match repeat_interval {
+        Some(repeat_interval) => raise(shared_state, message, chat_ids, Duration::from_secs(repeat_interval)).await,
+        None => shared_state.lock().await.outbox.push(&chat_ids, &message, None)
+    }
+}
+
+/// Periodically sends again the alerts which have not been acknowledged
+pub async fn run(shared_state: ProtectedSharedState) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut locked_shared_state = shared_state.lock().await;
+        for (alert_id, message, chat_ids) in locked_shared_state.alerts.due() {
+            // the chats which did not receive the previous repetition yet, during a Telegram outage for instance, are skipped
+            let chat_ids: Vec<ChatId> = chat_ids.into_iter().filter(|chat_id| !locked_shared_state.outbox.is_alert_queued(*chat_id, alert_id)).collect();
+            if chat_ids.is_empty() {
+                log::debug!("alert {} not acknowledged, its previous message is still queued", alert_id);
+                continue;
+            }
+            log::info!("alert {} not acknowledged, sending it again", alert_id);
+            locked_shared_state.outbox.push(&chat_ids, &message, Some(alert_id));
+        }
+    }
+}
@@ -1,33 +1,310 @@
 
 use std::{collections::HashMap, iter::FromIterator};
-use regex::Regex;
 use serde::Deserialize;
 use teloxide::types::ChatId;
 use derive_more::Deref;
 use thiserror::Error;
 use crate::log_level::LogLevel;
+use crate::tls;
+use crate::notifier::{Destinations, SinkName, SinkNames, TELEGRAM_SINK};
+use crate::sinks::Sink;
+use crate::rules::{Condition, Rule};
+use crate::regex_map::RegexMap;
+use crate::ordered_map::{self, OrderedMap};
+use crate::topic_filter::TopicFilter;
+use crate::template::{self, Template};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct MqttTls {
+    /// CA bundle used to verify the broker certificate
+    pub ca_file: Option<String>,
+
+    pub client_cert_file: Option<String>,
+
+    pub client_key_file: Option<String>,
+
+    /// Do not verify the broker certificate, only meant for lab setups
+    #[serde(default)]
+    pub insecure_skip_verify: bool
+}
+
+impl MqttTls {
+
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        if self.ca_file.is_none() && !self.insecure_skip_verify {
+            errors.push("mqtt_broker.tls: ca_file is required unless insecure_skip_verify is set".to_owned());
+        }
+
+        if self.client_cert_file.is_some() != self.client_key_file.is_some() {
+            errors.push("mqtt_broker.tls: client_cert_file and client_key_file must be specified together".to_owned());
+        }
+
+        if errors.is_empty() {
+            if let Err(tls_error) = tls::load_client_config(self) {
+                errors.push(format!("mqtt_broker.tls: {tls_error}"));
+            }
+        }
+
+        errors
+    }
+
+}
+
+fn mqtt_broker_hostname_default() -> String {
+    "localhost".to_owned()
+}
+
+fn mqtt_broker_port_default() -> u16 {
+    1883
+}
+
+fn mqtt_client_id_default() -> String {
+    "telegram-alarm-bot".to_owned()
+}
+
+fn mqtt_keep_alive_default() -> u64 {
+    5
+}
+
+fn mqtt_clean_session_default() -> bool {
+    true
+}
+
+fn mqtt_disconnection_grace_period_default() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
 pub struct MqttBroker {
+    #[serde(default = "mqtt_broker_hostname_default")]
     pub hostname: String,
-    pub port: u16
+
+    #[serde(default = "mqtt_broker_port_default")]
+    pub port: u16,
+
+    #[serde(default = "mqtt_client_id_default")]
+    pub client_id: String,
+
+    pub username: Option<String>,
+
+    pub password: Option<String>,
+
+    /// Keep alive interval in seconds
+    #[serde(default = "mqtt_keep_alive_default")]
+    pub keep_alive: u64,
+
+    #[serde(default = "mqtt_clean_session_default")]
+    pub clean_session: bool,
+
+    pub tls: Option<MqttTls>,
+
+    /// Time in seconds the broker connection can be lost before the admins are notified
+    #[serde(default = "mqtt_disconnection_grace_period_default")]
+    pub disconnection_grace_period: u64
+}
+
+impl Default for MqttBroker {
+    fn default() -> Self {
+        Self {
+            hostname: mqtt_broker_hostname_default(),
+            port: mqtt_broker_port_default(),
+            client_id: mqtt_client_id_default(),
+            username: None,
+            password: None,
+            keep_alive: mqtt_keep_alive_default(),
+            clean_session: mqtt_clean_session_default(),
+            tls: None,
+            disconnection_grace_period: mqtt_disconnection_grace_period_default()
+        }
+    }
+}
+
+impl MqttBroker {
+
+    pub fn credentials(&self) -> Option<(&String, &String)> {
+        self.username.as_ref().zip(self.password.as_ref())
+    }
+
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        if self.hostname.is_empty() {
+            errors.push("mqtt_broker: hostname must not be empty".to_owned());
+        }
+
+        if self.port == 0 {
+            errors.push("mqtt_broker: invalid port 0".to_owned());
+        }
+
+        if self.client_id.is_empty() || self.client_id.starts_with(' ') {
+            errors.push("mqtt_broker: client_id must not be empty or start with a space".to_owned());
+        }
+
+        if self.username.is_some() != self.password.is_some() {
+            errors.push("mqtt_broker: username and password must be specified together".to_owned());
+        }
+
+        if self.keep_alive < 5 {
+            errors.push("mqtt_broker: keep_alive must be at least 5 seconds".to_owned());
+        }
+
+        if let Some(tls) = &self.tls {
+            errors.extend(tls.errors());
+        }
+
+        errors
+    }
+
 }
 
 pub type SensorState = String;
-pub type SensorStateMessage = String;
+
+pub type RecipientGroupName = String;
+pub type Recipients = Vec<RecipientGroupName>;
+
+pub type ArmingMode = String;
+pub type ArmingModes = Vec<ArmingMode>;
+
+/// Built-in arming mode, the other modes are defined in the config
+pub const DISARMED_MODE: &str = "disarmed";
+
+/// Message template of a state, optionally sent to specific recipient groups and only in some arming modes
+#[derive(Debug, PartialEq)]
+pub struct SensorStateMessage {
+    pub template: Template,
+    pub recipients: Option<Recipients>,
+    pub modes: Option<ArmingModes>,
+
+    /// Interval in seconds at which the message is sent again until it is acknowledged
+    pub repeat_interval: Option<u64>,
+
+    /// Time in seconds the value has to hold before the message is sent
+    pub debounce: Option<u64>,
+
+    /// Sinks the message is sent to, "telegram" for the Telegram chats which are the only sink by default
+    pub sinks: Option<SinkNames>
+}
+
+// a state message is either a template or an object with the template and its options
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SensorStateMessageRepr {
+    Template(String),
+    TemplateWithOptions {
+        message: String,
+        recipients: Option<Recipients>,
+        modes: Option<ArmingModes>,
+        repeat_interval: Option<u64>,
+        debounce: Option<u64>,
+        sinks: Option<SinkNames>
+    }
+}
+
+impl<'de> Deserialize<'de> for SensorStateMessage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        match SensorStateMessageRepr::deserialize(deserializer)? {
+            SensorStateMessageRepr::Template(template_str) => Ok(Self {
+                template: template_str.parse().map_err(serde::de::Error::custom)?,
+                recipients: None,
+                modes: None,
+                repeat_interval: None,
+                debounce: None,
+                sinks: None
+            }),
+            SensorStateMessageRepr::TemplateWithOptions { message, recipients, modes, repeat_interval, debounce, sinks } => Ok(Self {
+                template: message.parse().map_err(serde::de::Error::custom)?,
+                recipients,
+                modes,
+                repeat_interval,
+                debounce,
+                sinks
+            })
+        }
+    }
+}
 
 pub type SensorStateMessagesInner = HashMap<SensorState, SensorStateMessage>;
 
-#[derive(Deserialize, Debug, Deref)]
-pub struct SensorStateMessages(SensorStateMessagesInner);
+pub type SensorRuleKey = String;
+
+#[derive(Debug, PartialEq)]
+pub struct SensorRule {
+    pub key: SensorRuleKey,
+    pub rule: Rule,
+    pub message: SensorStateMessage
+}
+
+// exact value matches are accessed through Deref, numeric rules through rules()
+#[derive(Debug, Deref, PartialEq)]
+pub struct SensorStateMessages {
+    #[deref]
+    exact: SensorStateMessagesInner,
+    rules: Vec<SensorRule>
+}
+
+impl SensorStateMessages {
+
+    // string values can be used as keys without the JSON quotes: "ON" as well as "\"ON\""
+    pub fn message(&self, value: &serde_json::Value) -> Option<&SensorStateMessage> {
+        self.exact.get(value.to_string().as_str()).or_else(|| match value {
+            serde_json::Value::String(value_str) => self.exact.get(value_str),
+            _ => None
+        })
+    }
+
+    pub fn rules(&self) -> &Vec<SensorRule> {
+        &self.rules
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &SensorStateMessage> {
+        self.exact.values().chain(self.rules.iter().map(|sensor_rule| &sensor_rule.message))
+    }
+
+}
+
+impl<'de> Deserialize<'de> for SensorStateMessages {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let all: SensorStateMessagesInner = Deserialize::deserialize(deserializer)?;
+        let mut exact = SensorStateMessagesInner::new();
+        let mut rules = vec![];
+
+        for (key, message) in all {
+            if Rule::is_rule(&key) {
+                let rule = key.parse().map_err(serde::de::Error::custom)?;
+                rules.push(SensorRule { key, rule, message });
+            } else {
+                exact.insert(key, message);
+            }
+        }
+
+        Ok(Self { exact, rules })
+    }
+}
 
-pub type SensorNameRegex = String;
 pub type PayloadFieldName = String;
 
 pub type SensorPayloadFieldNameAndStateMessagesInner = HashMap<PayloadFieldName, SensorStateMessages>;
 
-#[derive(Deserialize, Debug, Deref)]
-pub struct SensorPayloadFieldNameAndStateMessages(SensorPayloadFieldNameAndStateMessagesInner);
+/// Keys of the options of a sensor pattern, payload fields with these names can be selected as `/recipients` and `/modes`
+const SENSOR_RECIPIENTS_KEY: &str = "recipients";
+const SENSOR_MODES_KEY: &str = "modes";
+
+// payload fields are accessed through Deref
+#[derive(Debug, Deref, PartialEq)]
+pub struct SensorPayloadFieldNameAndStateMessages {
+    #[deref]
+    fields: SensorPayloadFieldNameAndStateMessagesInner,
+    recipients: Option<Recipients>,
+    modes: Option<ArmingModes>
+}
 
 impl SensorPayloadFieldNameAndStateMessages {
 
@@ -35,58 +312,310 @@ impl SensorPayloadFieldNameAndStateMessages {
         self.keys().collect()
     }
 
+    /// Recipient groups of the sensor messages which do not define their own
+    pub fn recipients(&self) -> Option<&Recipients> {
+        self.recipients.as_ref()
+    }
+
+    /// Arming modes of the sensor messages which do not define their own
+    pub fn modes(&self) -> Option<&ArmingModes> {
+        self.modes.as_ref()
+    }
+
+}
+
+impl<'de> Deserialize<'de> for SensorPayloadFieldNameAndStateMessages {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let all: HashMap<String, serde_json::Value> = Deserialize::deserialize(deserializer)?;
+        let mut fields = SensorPayloadFieldNameAndStateMessagesInner::new();
+        let mut recipients = None;
+        let mut modes = None;
+
+        for (key, value) in all {
+            if key == SENSOR_RECIPIENTS_KEY {
+                recipients = Some(serde_json::from_value(value).map_err(serde::de::Error::custom)?);
+            } else if key == SENSOR_MODES_KEY {
+                modes = Some(serde_json::from_value(value).map_err(serde::de::Error::custom)?);
+            } else {
+                fields.insert(key, serde_json::from_value(value).map_err(serde::de::Error::custom)?);
+            }
+        }
+
+        Ok(Self { fields, recipients, modes })
+    }
 }
 
 pub type SensorName = String;
 pub type SensorNameCaptures = HashMap<String, Option<String>>;
-pub type SensorsInner = HashMap<SensorNameRegex, SensorPayloadFieldNameAndStateMessages>;
+pub type SensorsInner = RegexMap<SensorPayloadFieldNameAndStateMessages>;
 
-#[derive(Deserialize, Debug, Deref)]
+#[derive(Deserialize, Debug, Deref, PartialEq)]
 pub struct Sensors(SensorsInner);
 
+/// Topic filter and sensor name regex of a sensor pattern, identifies the pattern among those matching a topic
+pub type SensorPattern = String;
+
+pub type SensorMatch<'a> = (SensorName, SensorNameCaptures, &'a SensorPayloadFieldNameAndStateMessages, SensorPattern);
+
 impl Sensors {
 
-    // returns: captures, sensor_payload_field_names_and_state_messages
-    pub fn match_sensor_name(&self, sensor_name: &str) -> Result<Option<(SensorName, SensorNameCaptures, &SensorPayloadFieldNameAndStateMessages)>, regex::Error> {
-        for (sensor_name_re_str, payload_field_name_and_state_messages) in self.0.iter() {
-            let re = Regex::new(sensor_name_re_str)?;
-            if let Some(captures) = re.captures(sensor_name) {
-                let sensor_name = captures.get(0).unwrap().as_str().to_string();
-                let name_captures: SensorNameCaptures = HashMap::from_iter(re.capture_names().skip(1).map(|cname| {
-                    let cname = cname.unwrap();
-                    let cstr = captures.name(cname).map(|ncap| ncap.as_str().to_string());
-                    (cname.to_string(), cstr)
-                }));
-                return Ok(Some((sensor_name, name_captures, payload_field_name_and_state_messages)));
+    // returns the matching patterns in config order, only the first one unless match_all is set
+    pub fn match_sensor_name(&self, sensor_name: &str, match_all: bool) -> Vec<SensorMatch<'_>> {
+        let matches = self.0.matches(sensor_name).filter_map(|(re, payload_field_name_and_state_messages)| {
+            let captures = re.captures(sensor_name)?;
+            let sensor_name = captures.get(0).unwrap().as_str().to_string();
+            let name_captures: SensorNameCaptures = HashMap::from_iter(re.capture_names().flatten().map(|cname| {
+                let cstr = captures.name(cname).map(|ncap| ncap.as_str().to_string());
+                (cname.to_string(), cstr)
+            }));
+            Some((sensor_name, name_captures, payload_field_name_and_state_messages, re.as_str().to_owned()))
+        });
+
+        if match_all { matches.collect() } else { matches.take(1).collect() }
+    }
+
+    // returns: (shadowed pattern, shadowing pattern) pairs of patterns which can never be matched because a pattern
+    // listed before them already matches all the names they match: either a catch-all such as ".*", or for the literal
+    // patterns, a pattern matching the literal anchored at both ends, which only matches itself, or an unanchored
+    // literal contained in the literal
+    pub fn shadowed_patterns(&self) -> Vec<(&str, &str)> {
+        let mut shadowed = vec![];
+        for (index, (re, _)) in self.0.iter().enumerate() {
+            let pattern = re.as_str();
+            let shadowing = self.0.iter().take(index).find(|(prev_re, _)| {
+                let prev_pattern = prev_re.as_str();
+                if matches_any_name(prev_pattern) {
+                    return true;
+                }
+                match (pattern_literal(pattern), pattern_literal(prev_pattern)) {
+                    (Some((literal, (true, true))), _) => prev_re.is_match(literal),
+                    (Some((literal, _)), Some((prev_literal, (false, false)))) => literal.contains(prev_literal),
+                    _ => false
+                }
+            });
+            if let Some((shadowing_re, _)) = shadowing {
+                shadowed.push((pattern, shadowing_re.as_str()));
             }
         }
-        Ok(None)
+        shadowed
+    }
+
+}
+
+// returns: the text matched by a pattern without special characters and whether it is anchored at its (start, end)
+fn pattern_literal(pattern: &str) -> Option<(&str, (bool, bool))> {
+    let literal = pattern.strip_prefix('^').unwrap_or(pattern);
+    let literal = literal.strip_suffix('$').unwrap_or(literal);
+    if literal.chars().any(|c| "\\.+*?()|[]{}^$".contains(c)) {
+        return None;
+    }
+    Some((literal, (pattern.starts_with('^'), pattern.ends_with('$'))))
+}
+
+// whether a pattern is a catch-all matching every sensor name, such as ".*" or "^.+$"
+fn matches_any_name(pattern: &str) -> bool {
+    let unanchored = pattern.strip_prefix('^').unwrap_or(pattern);
+    let unanchored = unanchored.strip_suffix('$').unwrap_or(unanchored);
+    matches!(unanchored, ".*" | ".+") || matches!(pattern, "" | "." | "^" | "$")
+}
+
+pub type MaxSilenceSeconds = u64;
+pub type MaxSilenceInner = RegexMap<MaxSilenceSeconds>;
+
+#[derive(Deserialize, Debug, Deref, Default, PartialEq)]
+pub struct MaxSilence(MaxSilenceInner);
+
+impl MaxSilence {
+
+    // returns the shortest max silence of the patterns matching the sensor name
+    pub fn match_sensor_name(&self, sensor_name: &str) -> Option<MaxSilenceSeconds> {
+        self.0.matches(sensor_name).map(|(_, seconds)| *seconds).min()
     }
 
 }
 
-pub type MqttTopicBase = String;
-pub type MqttTopicsInner = HashMap<MqttTopicBase, Sensors>;
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct BatteryThresholds {
+    pub percent: Option<u8>,
 
-#[derive(Deserialize, Debug, Deref)]
-pub struct MqttTopics(MqttTopicsInner);
+    /// In volts
+    pub voltage: Option<f32>
+}
+
+impl BatteryThresholds {
+
+    // thresholds defined in other take precedence
+    fn merge(&self, other: &BatteryThresholds) -> BatteryThresholds {
+        BatteryThresholds {
+            percent: other.percent.or(self.percent),
+            voltage: other.voltage.or(self.voltage)
+        }
+    }
+
+    // keeps the highest threshold of both
+    fn max(&self, other: &BatteryThresholds) -> BatteryThresholds {
+        BatteryThresholds {
+            percent: self.percent.max(other.percent),
+            voltage: match (self.voltage, other.voltage) {
+                (Some(voltage), Some(other_voltage)) => Some(voltage.max(other_voltage)),
+                (voltage, other_voltage) => voltage.or(other_voltage)
+            }
+        }
+    }
+
+}
+
+fn low_battery_percent_hysteresis_default() -> u8 {
+    5
+}
+
+fn low_battery_voltage_hysteresis_default() -> f32 {
+    0.1
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct LowBattery {
+    #[serde(default)]
+    pub default: BatteryThresholds,
+
+    #[serde(default)]
+    pub sensors: RegexMap<BatteryThresholds>,
+
+    /// How many percent the battery level has to go back above the threshold before another notification can be sent
+    #[serde(default = "low_battery_percent_hysteresis_default")]
+    pub percent_hysteresis: u8,
+
+    /// How many volts the battery voltage has to go back above the threshold before another notification can be sent
+    #[serde(default = "low_battery_voltage_hysteresis_default")]
+    pub voltage_hysteresis: f32,
+
+    /// Recipient groups of the low battery messages, the notification chats by default
+    #[serde(default)]
+    pub recipients: Option<Recipients>
+}
+
+impl Default for LowBattery {
+    fn default() -> Self {
+        Self {
+            default: Default::default(),
+            sensors: Default::default(),
+            percent_hysteresis: low_battery_percent_hysteresis_default(),
+            voltage_hysteresis: low_battery_voltage_hysteresis_default(),
+            recipients: None
+        }
+    }
+}
+
+impl LowBattery {
+
+    // returns the default thresholds overridden by the ones of the patterns matching the sensor name,
+    // the highest threshold wins when several patterns match
+    pub fn match_sensor_name(&self, sensor_name: &str) -> BatteryThresholds {
+        let overrides = self.sensors.matches(sensor_name)
+            .map(|(_, thresholds)| *thresholds)
+            .reduce(|current, thresholds| current.max(&thresholds));
+        match overrides {
+            Some(overrides) => self.default.merge(&overrides),
+            None => self.default
+        }
+    }
+
+}
+
+pub type MqttTopicsInner = OrderedMap<TopicFilter, Sensors>;
+
+#[derive(Deserialize, Debug, Deref, PartialEq)]
+pub struct MqttTopics(#[serde(deserialize_with = "ordered_map::deserialize")] MqttTopicsInner);
 
 impl MqttTopics {
 
-    // returns: captures, sensor_payload_field_names_and_state_messages
-    pub fn match_topic(&self, topic: &str) -> Result<Option<(SensorName, SensorNameCaptures, &SensorPayloadFieldNameAndStateMessages)>, regex::Error> {
-        let tmatch = self.0.iter().find(|(topic_base, _)| {
-            let base_slash = (*topic_base).clone() + "/";
-            topic.starts_with(&base_slash)
-        });
+    // topic filters are tried in config order, the named wildcards captures are added to the sensor name captures
+    // which take precedence over them
+    pub fn match_topic(&self, topic: &str, match_all: bool) -> Vec<SensorMatch<'_>> {
+        let mut sensor_matches = vec![];
+
+        for (topic_filter, sensors) in self.0.iter() {
+            let Some(topic_match) = topic_filter.match_topic(topic) else {
+                continue;
+            };
+
+            let filter_matches = sensors.match_sensor_name(&topic_match.sensor_name, match_all).into_iter()
+                .map(|(sensor_name, name_captures, payload_field_name_and_state_messages, pattern)| {
+                    let mut captures: SensorNameCaptures = topic_match.captures.iter()
+                        .map(|(name, value)| (name.clone(), Some(value.clone())))
+                        .collect();
+                    captures.extend(name_captures);
+                    (sensor_name, captures, payload_field_name_and_state_messages, format!("{topic_filter} {pattern}"))
+                });
+            sensor_matches.extend(filter_matches);
 
-        match tmatch {
-            Some((topic_base, sensors)) => {
-                let sensor_name = &topic[topic_base.len()+1..];
-                sensors.match_sensor_name(sensor_name)
-            },
-            None => Ok(None),
+            if !match_all && !sensor_matches.is_empty() {
+                break;
+            }
         }
+
+        sensor_matches
+    }
+
+    /// Recipients of the sensor pattern first matching a topic, for the messages about the sensor itself
+    pub fn sensor_recipients(&self, topic: &str) -> Option<&Recipients> {
+        self.match_topic(topic, false).into_iter().next().and_then(|(_, _, payload_field_name_and_state_messages, _)| payload_field_name_and_state_messages.recipients())
+    }
+
+}
+
+/// A payload field is flapping when its value changes more than max_transitions times within window seconds
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct FlapDetection {
+    pub max_transitions: usize,
+    pub window: u64
+}
+
+/// Change of the notifications state and/or arming mode at a time of the day, on some days of the week or every day
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct ScheduledChange {
+    #[serde(deserialize_with = "schedule_times::deserialize_time")]
+    pub at: chrono::NaiveTime,
+
+    #[serde(default, deserialize_with = "schedule_times::deserialize_weekdays")]
+    pub days: Vec<chrono::Weekday>,
+
+    pub mode: Option<ArmingMode>,
+
+    pub notifications: Option<bool>
+}
+
+impl ScheduledChange {
+
+    pub fn is_on(&self, weekday: chrono::Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&weekday)
+    }
+
+}
+
+mod schedule_times {
+    use serde::Deserialize;
+
+    pub fn deserialize_time<'de, D>(deserializer: D) -> Result<chrono::NaiveTime, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let time_str = String::deserialize(deserializer)?;
+        chrono::NaiveTime::parse_from_str(&time_str, "%H:%M")
+            .map_err(|_| serde::de::Error::custom(format!("invalid time {time_str:?}, expected HH:MM")))
+    }
+
+    pub fn deserialize_weekdays<'de, D>(deserializer: D) -> Result<Vec<chrono::Weekday>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let weekday_strs: Vec<String> = Deserialize::deserialize(deserializer)?;
+        weekday_strs.iter().map(|weekday_str| {
+            weekday_str.parse().map_err(|_| serde::de::Error::custom(format!("invalid day {weekday_str:?}, expected mon, tue...")))
+        }).collect()
     }
 
 }
@@ -112,7 +641,112 @@ mod chat_ids {
 
 }
 
-#[derive(Deserialize, Debug)]
+/// Chats of a recipient group and the other sinks its messages are also sent to
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RecipientGroup {
+    pub chat_ids: Vec<ChatId>,
+    pub sinks: SinkNames
+}
+
+// a recipient group is either a list of chat IDs or an object with its chat IDs and sinks
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RecipientGroupRepr {
+    ChatIds(Vec<i64>),
+    WithSinks {
+        #[serde(default)]
+        chat_ids: Vec<i64>,
+        #[serde(default)]
+        sinks: SinkNames
+    }
+}
+
+impl<'de> Deserialize<'de> for RecipientGroup {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let (chat_ids, sinks) = match RecipientGroupRepr::deserialize(deserializer)? {
+            RecipientGroupRepr::ChatIds(chat_ids) => (chat_ids, vec![]),
+            RecipientGroupRepr::WithSinks { chat_ids, sinks } => (chat_ids, sinks)
+        };
+        Ok(Self { chat_ids: chat_ids.into_iter().map(ChatId).collect(), sinks })
+    }
+}
+
+fn rate_limit_global_per_second_default() -> u32 {
+    30
+}
+
+fn rate_limit_chat_per_minute_default() -> u32 {
+    20
+}
+
+fn rate_limit_digest_threshold_default() -> usize {
+    3
+}
+
+/// Outgoing notifications rate limits, Telegram throttles bots above them
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct RateLimit {
+    #[serde(default = "rate_limit_global_per_second_default")]
+    pub global_per_second: u32,
+
+    #[serde(default = "rate_limit_chat_per_minute_default")]
+    pub chat_per_minute: u32,
+
+    /// Number of notifications waiting for a chat from which they are sent as a single digest message
+    #[serde(default = "rate_limit_digest_threshold_default")]
+    pub digest_threshold: usize
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            global_per_second: rate_limit_global_per_second_default(),
+            chat_per_minute: rate_limit_chat_per_minute_default(),
+            digest_threshold: rate_limit_digest_threshold_default()
+        }
+    }
+}
+
+impl RateLimit {
+
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        if self.global_per_second == 0 || self.chat_per_minute == 0 {
+            errors.push("telegram.rate_limit: global_per_second and chat_per_minute must be greater than 0".to_owned());
+        }
+
+        if self.digest_threshold < 2 {
+            errors.push("telegram.rate_limit: digest_threshold must be at least 2".to_owned());
+        }
+
+        errors
+    }
+
+}
+
+fn fallback_failures_default() -> u32 {
+    3
+}
+
+/// Sinks the notifications are also sent to while Telegram cannot be reached
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Fallback {
+    /// Consecutive failures to send to Telegram from which the fallback sinks are used
+    #[serde(default = "fallback_failures_default")]
+    pub failures: u32,
+
+    pub sinks: SinkNames
+}
+
+fn telegram_delayed_notification_age_default() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
 pub struct Telegram {
     pub token: String,
 
@@ -120,29 +754,91 @@ pub struct Telegram {
     pub notification_chat_ids: Vec<ChatId>,
 
     #[serde(default, deserialize_with = "chat_ids::deserialize_option")]
-    pub admin_chat_ids: Option<Vec<ChatId>>
+    pub admin_chat_ids: Option<Vec<ChatId>>,
+
+    #[serde(default)]
+    pub recipient_groups: HashMap<RecipientGroupName, RecipientGroup>,
+
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+
+    /// Age in seconds from which a notification is sent with the time of its event
+    #[serde(default = "telegram_delayed_notification_age_default")]
+    pub delayed_notification_age: u64,
+
+    pub fallback: Option<Fallback>
 }
 
 impl Telegram {
 
+    pub fn is_admin_chat_id(&self, chat_id: &ChatId) -> bool {
+        self.admin_chat_ids.as_ref().is_some_and(|admin_chat_ids| admin_chat_ids.contains(chat_id))
+    }
+
+    /// Chats allowed to send commands
     pub fn valid_chat_ids(&self) -> Vec<ChatId> {
-        self.notification_chat_ids.clone().into_iter().chain(self.admin_chat_ids.clone().unwrap_or_default().into_iter()).collect()
+        self.notification_chat_ids.clone().into_iter().chain(self.admin_chat_ids.clone().unwrap_or_default()).collect()
+    }
+
+    /// Chats which can receive notifications, the chats of the recipient groups can only acknowledge or snooze alerts
+    pub fn notified_chat_ids(&self) -> Vec<ChatId> {
+        self.valid_chat_ids().into_iter()
+            .chain(self.recipient_groups.values().flat_map(|group| group.chat_ids.iter().copied()))
+            .collect()
+    }
+
+    /// Chats of the recipient groups, the notification chats when no groups are specified
+    pub fn chat_ids(&self, recipients: Option<&Recipients>) -> Vec<ChatId> {
+        let Some(recipients) = recipients else {
+            return self.notification_chat_ids.clone();
+        };
+
+        let mut chat_ids: Vec<ChatId> = vec![];
+        for group in recipients.iter().filter_map(|group_name| self.recipient_groups.get(group_name)) {
+            for chat_id in &group.chat_ids {
+                if !chat_ids.contains(chat_id) {
+                    chat_ids.push(*chat_id);
+                }
+            }
+        }
+        chat_ids
+    }
+
+    pub fn recipients_errors(&self, recipients: Option<&Recipients>, context: &str) -> Vec<String> {
+        recipients.into_iter().flatten()
+            .filter(|group_name| !self.recipient_groups.contains_key(*group_name))
+            .map(|group_name| format!("{context}: unknown recipient group {group_name:?}"))
+            .collect()
     }
 
 }
 
 #[derive(Debug, Error)]
 pub enum ConfigFileLoadError {
-    #[error("IO error")]
+    #[error("IO error: {0}")]
     IOError(std::io::Error),
-    #[error("deserialization error")]
+    #[error("deserialization error: {0}")]
     DeserializationError(serde_json::Error)
 }
 
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StartupNotifications {
+    Enabled,
+    #[default]
+    Disabled,
+    /// As they were when the bot was stopped
+    Restore
+}
+
 fn sensors_data_file_default() -> String {
     "sensors_data.json".to_owned()
 }
 
+fn autosave_interval_default() -> u64 {
+    300
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     #[serde(default)]
@@ -151,44 +847,344 @@ pub struct Config {
     #[serde(default = "sensors_data_file_default")]
     pub sensors_data_file: String,
 
-    pub mqtt_broker: Option<MqttBroker>,
+    /// Interval in seconds at which the sensors data file is saved, 0 to only save it when the bot is stopped
+    #[serde(default = "autosave_interval_default")]
+    pub autosave_interval: u64,
+
+    /// Whether the notifications are enabled when the bot starts
+    #[serde(default)]
+    pub startup_notifications: StartupNotifications,
+
+    #[serde(default)]
+    pub mqtt_broker: MqttBroker,
 
     pub telegram: Telegram,
 
     #[serde(rename = "sensors")]
-    pub mqtt_topics: MqttTopics
+    pub mqtt_topics: MqttTopics,
+
+    /// Whether a sensor name can match several patterns, otherwise only the first matching pattern in config order is used
+    #[serde(default)]
+    pub match_all_sensor_patterns: bool,
+
+    #[serde(default)]
+    pub max_silence: MaxSilence,
+
+    #[serde(default)]
+    pub low_battery: LowBattery,
+
+    /// Arming modes besides the built-in "disarmed" mode
+    #[serde(default)]
+    pub arming_modes: ArmingModes,
+
+    #[serde(default)]
+    pub schedule: Vec<ScheduledChange>,
+
+    pub flap_detection: Option<FlapDetection>,
+
+    /// Notification sinks besides Telegram, referenced by name by the messages and recipient groups
+    #[serde(default)]
+    pub sinks: HashMap<SinkName, Sink>
 }
 
 impl Config {
 
+    pub fn is_arming_mode(&self, mode: &str) -> bool {
+        mode == DISARMED_MODE || self.arming_modes.iter().any(|arming_mode| arming_mode == mode)
+    }
+
+    /// Whether arming modes are defined, otherwise the messages are sent whatever the mode
+    pub fn uses_arming_modes(&self) -> bool {
+        !self.arming_modes.is_empty()
+    }
+
+    // messages which do not list arming modes are active in all of them except disarmed
+    pub fn is_active_in_mode(&self, modes: Option<&ArmingModes>, mode: &str) -> bool {
+        if !self.uses_arming_modes() {
+            return true;
+        }
+        match modes {
+            Some(modes) => modes.iter().any(|active_mode| active_mode == mode),
+            None => mode != DISARMED_MODE
+        }
+    }
+
+    fn modes_errors(&self, modes: Option<&ArmingModes>, context: &str) -> Vec<String> {
+        modes.into_iter().flatten()
+            .filter(|mode| !self.is_arming_mode(mode))
+            .map(|mode| format!("{context}: unknown arming mode {mode:?}"))
+            .collect()
+    }
+
+    /// Telegram chats and sinks of a message: its own sinks, only "telegram" by default, and those of its recipient groups
+    pub fn destinations(&self, recipients: Option<&Recipients>, sinks: Option<&SinkNames>) -> Destinations {
+        let chat_ids = match sinks {
+            Some(sinks) if !sinks.iter().any(|sink_name| sink_name == TELEGRAM_SINK) => vec![],
+            _ => self.telegram.chat_ids(recipients)
+        };
+
+        let group_sinks = recipients.into_iter().flatten()
+            .filter_map(|group_name| self.telegram.recipient_groups.get(group_name))
+            .flat_map(|group| group.sinks.iter());
+
+        let mut destination_sinks: SinkNames = vec![];
+        for sink_name in sinks.into_iter().flatten().chain(group_sinks) {
+            if sink_name != TELEGRAM_SINK && !destination_sinks.contains(sink_name) {
+                destination_sinks.push(sink_name.clone());
+            }
+        }
+
+        Destinations { chat_ids, sinks: destination_sinks }
+    }
+
+    fn sink_names_errors(&self, sinks: Option<&SinkNames>, context: &str) -> Vec<String> {
+        sinks.into_iter().flatten()
+            .filter(|sink_name| *sink_name != TELEGRAM_SINK && !self.sinks.contains_key(*sink_name))
+            .map(|sink_name| format!("{context}: unknown sink {sink_name:?}"))
+            .collect()
+    }
+
+    fn sinks_errors(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        for (sink_name, sink) in &self.sinks {
+            if sink_name == TELEGRAM_SINK {
+                errors.push(format!("sinks: {TELEGRAM_SINK:?} is a built-in sink"));
+            }
+            errors.extend(sink.errors(&format!("sinks.{sink_name}")));
+        }
+
+        for (group_name, group) in &self.telegram.recipient_groups {
+            errors.extend(self.sink_names_errors(Some(&group.sinks), &format!("telegram.recipient_groups.{group_name}")));
+        }
+
+        if let Some(fallback) = &self.telegram.fallback {
+            if fallback.failures == 0 {
+                errors.push("telegram.fallback: failures must be greater than 0".to_owned());
+            }
+            if fallback.sinks.is_empty() || fallback.sinks.iter().any(|sink_name| sink_name == TELEGRAM_SINK) {
+                errors.push(format!("telegram.fallback: at least one sink other than {TELEGRAM_SINK:?} is required"));
+            }
+            errors.extend(self.sink_names_errors(Some(&fallback.sinks), "telegram.fallback"));
+        }
+
+        errors
+    }
+
     pub fn load_from_file(path: &str) -> Result<Self, ConfigFileLoadError> {
         let file = std::fs::File::open(path).map_err(ConfigFileLoadError::IOError)?;
         let reader = std::io::BufReader::new(file);
         serde_json::from_reader(reader).map_err(ConfigFileLoadError::DeserializationError)
     }
 
-    pub fn mqtt_topics(&self) -> Vec<&String> {
-        self.mqtt_topics.0.keys().collect()
+    pub fn mqtt_topics(&self) -> Vec<&TopicFilter> {
+        self.mqtt_topics.0.iter().map(|(mqtt_topic, _)| mqtt_topic).collect()
     }
 
     pub fn mqtt_subscribe_patterns(&self) -> Vec<String> {
-        self.mqtt_topics.0.keys().map(|mqtt_topic| format!("{mqtt_topic}/+")).collect()
+        let mut subscribe_patterns: Vec<String> = vec![];
+        for mqtt_topic in self.mqtt_topics() {
+            let subscribe_pattern = mqtt_topic.subscribe_pattern();
+            if !subscribe_patterns.contains(&subscribe_pattern) {
+                subscribe_patterns.push(subscribe_pattern);
+            }
+        }
+        subscribe_patterns
     }
 
-    pub fn check(&self) -> bool {
-        let mut config_good = true;
-
-        // check sensor name regexes
-        for (_, sensors) in self.mqtt_topics.iter() {
-            for (sensor_name_re, _) in sensors.iter() {
-                if let Err(re_error) = Regex::new(sensor_name_re) {
-                    eprintln!("\n{re_error}");
-                    config_good = false;
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+
+        if !self.match_all_sensor_patterns {
+            for (topic_base, sensors) in self.mqtt_topics.iter() {
+                for (shadowed, shadowing) in sensors.shadowed_patterns() {
+                    warnings.push(format!("sensors.{topic_base}: pattern {shadowed:?} is shadowed by {shadowing:?} listed before it"));
                 }
             }
         }
 
-        config_good
+        warnings
+    }
+
+    /// Describes the differences with another configuration
+    pub fn changes(&self, new_config: &Config) -> Vec<String> {
+        let mut changes = vec![];
+
+        let subscribe_patterns = self.mqtt_subscribe_patterns();
+        let new_subscribe_patterns = new_config.mqtt_subscribe_patterns();
+
+        for pattern in new_subscribe_patterns.iter().filter(|pattern| !subscribe_patterns.contains(pattern)) {
+            changes.push(format!("subscribed to {pattern}"));
+        }
+
+        for pattern in subscribe_patterns.iter().filter(|pattern| !new_subscribe_patterns.contains(pattern)) {
+            changes.push(format!("unsubscribed from {pattern}"));
+        }
+
+        let section_changes = [
+            (self.mqtt_broker != new_config.mqtt_broker, "MQTT broker settings, reconnecting"),
+            (self.mqtt_topics != new_config.mqtt_topics, "sensors"),
+            (self.match_all_sensor_patterns != new_config.match_all_sensor_patterns, "sensor patterns matching"),
+            (self.max_silence != new_config.max_silence, "max silence"),
+            (self.low_battery != new_config.low_battery, "low battery thresholds"),
+            (self.telegram.notification_chat_ids != new_config.telegram.notification_chat_ids, "notification chat IDs"),
+            (self.telegram.admin_chat_ids != new_config.telegram.admin_chat_ids, "admin chat IDs"),
+            (self.telegram.recipient_groups != new_config.telegram.recipient_groups, "recipient groups"),
+            (self.telegram.rate_limit != new_config.telegram.rate_limit, "rate limits"),
+            (self.telegram.delayed_notification_age != new_config.telegram.delayed_notification_age, "delayed notification age"),
+            (self.telegram.fallback != new_config.telegram.fallback, "Telegram fallback"),
+            (self.telegram.token != new_config.telegram.token, "Telegram token (requires a restart)"),
+            (self.log_level != new_config.log_level, "log level (requires a restart)"),
+            (self.sensors_data_file != new_config.sensors_data_file, "sensors data file (requires a restart)"),
+            (self.autosave_interval != new_config.autosave_interval, "autosave interval"),
+            (self.startup_notifications != new_config.startup_notifications, "startup notifications"),
+            (self.arming_modes != new_config.arming_modes, "arming modes"),
+            (self.schedule != new_config.schedule, "schedule"),
+            (self.flap_detection != new_config.flap_detection, "flap detection"),
+            (self.sinks != new_config.sinks, "sinks")
+        ];
+
+        for (changed, section) in section_changes {
+            if changed {
+                changes.push(section.to_owned());
+            }
+        }
+
+        changes
+    }
+
+    // the template variables must be either built-in, payload fields or named captures of the topic filter or sensor name pattern,
+    // the recipient groups must be defined in the telegram section, the arming modes in arming_modes and the sinks in sinks
+    fn sensors_errors(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        for (topic_filter, sensors) in self.mqtt_topics.iter() {
+            for (re, payload_field_names_and_state_messages) in sensors.iter() {
+                errors.extend(self.telegram.recipients_errors(payload_field_names_and_state_messages.recipients(), &format!("sensors.{topic_filter}.{}", re.as_str())));
+                errors.extend(self.modes_errors(payload_field_names_and_state_messages.modes(), &format!("sensors.{topic_filter}.{}", re.as_str())));
+
+                for (payload_field_name, state_messages) in payload_field_names_and_state_messages.iter() {
+                    for state_message in state_messages.messages() {
+                        errors.extend(self.telegram.recipients_errors(state_message.recipients.as_ref(), &format!("sensors.{topic_filter}.{}.{payload_field_name}", re.as_str())));
+                        errors.extend(self.modes_errors(state_message.modes.as_ref(), &format!("sensors.{topic_filter}.{}.{payload_field_name}", re.as_str())));
+                        errors.extend(self.sink_names_errors(state_message.sinks.as_ref(), &format!("sensors.{topic_filter}.{}.{payload_field_name}", re.as_str())));
+                        if state_message.repeat_interval == Some(0) {
+                            errors.push(format!("sensors.{topic_filter}.{}.{payload_field_name}: repeat_interval must be greater than 0", re.as_str()));
+                        }
+                        if state_message.debounce == Some(0) {
+                            errors.push(format!("sensors.{topic_filter}.{}.{payload_field_name}: debounce must be greater than 0", re.as_str()));
+                        }
+                    }
+
+                    // a "changed" rule fires once per change, there is no state to hold
+                    for sensor_rule in state_messages.rules() {
+                        if sensor_rule.message.debounce.is_some() && matches!(sensor_rule.rule.condition, Condition::ChangedBy(_)) {
+                            errors.push(format!("sensors.{topic_filter}.{}.{payload_field_name}: debounce is not supported by the rule {:?}", re.as_str(), sensor_rule.key));
+                        }
+                    }
+
+                    for variable in state_messages.messages().flat_map(|state_message| state_message.template.variables()) {
+                        let is_known = template::VARIABLES.contains(&variable)
+                            || variable.strip_prefix(template::PAYLOAD_VARIABLE_PREFIX).is_some_and(|field_name| !field_name.is_empty())
+                            || topic_filter.wildcard_names().any(|name| name == variable)
+                            || re.capture_names().flatten().any(|name| name == variable);
+                        if !is_known {
+                            errors.push(format!("sensors.{topic_filter}.{}.{payload_field_name}: unknown template variable {variable:?}", re.as_str()));
+                        }
+                    }
+                }
+            }
+        }
+
+        errors
+    }
+
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = self.mqtt_broker.errors();
+
+        if self.arming_modes.iter().any(|mode| mode == DISARMED_MODE) {
+            errors.push(format!("arming_modes: {DISARMED_MODE:?} is a built-in mode"));
+        }
+
+        if let Some(flap_detection) = &self.flap_detection {
+            if flap_detection.max_transitions == 0 || flap_detection.window == 0 {
+                errors.push("flap_detection: max_transitions and window must be greater than 0".to_owned());
+            }
+        }
+
+        for (index, scheduled_change) in self.schedule.iter().enumerate() {
+            if scheduled_change.mode.is_none() && scheduled_change.notifications.is_none() {
+                errors.push(format!("schedule[{index}]: mode and/or notifications must be specified"));
+            }
+            if let Some(mode) = &scheduled_change.mode {
+                if !self.is_arming_mode(mode) {
+                    errors.push(format!("schedule[{index}]: unknown arming mode {mode:?}"));
+                }
+            }
+        }
+
+        errors.extend(self.telegram.rate_limit.errors());
+        errors.extend(self.sinks_errors());
+        errors.extend(self.sensors_errors());
+        errors.extend(self.telegram.recipients_errors(self.low_battery.recipients.as_ref(), "low_battery"));
+        errors
+    }
+
+    pub fn check(&self) -> bool {
+        let errors = self.errors();
+
+        for error in &errors {
+            eprintln!("\n{error}");
+        }
+
+        errors.is_empty()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shadowed_patterns(patterns: &[&str]) -> Vec<(String, String)> {
+        let sensors_json = format!("{{{}}}", patterns.iter().map(|pattern| format!("{pattern:?}: {{}}")).collect::<Vec<String>>().join(", "));
+        let sensors: Sensors = serde_json::from_str(&sensors_json).unwrap();
+        sensors.shadowed_patterns().into_iter().map(|(shadowed, shadowing)| (shadowed.to_owned(), shadowing.to_owned())).collect()
+    }
+
+    fn pair(shadowed: &str, shadowing: &str) -> (String, String) {
+        (shadowed.to_owned(), shadowing.to_owned())
+    }
+
+    #[test]
+    fn catch_all_shadows_everything() {
+        assert_eq!(shadowed_patterns(&[".*", "door"]), vec![pair("door", ".*")]);
+        assert_eq!(shadowed_patterns(&["^.*$", "^door$", "^Motion.*"]), vec![pair("^door$", "^.*$"), pair("^Motion.*", "^.*$")]);
+        assert_eq!(shadowed_patterns(&[".+", "door"]), vec![pair("door", ".+")]);
+        assert_eq!(shadowed_patterns(&["door", ".*"]), vec![]);
+    }
+
+    #[test]
+    fn anchored_literal_shadowed() {
+        assert_eq!(shadowed_patterns(&["door", "^front door$"]), vec![pair("^front door$", "door")]);
+        assert_eq!(shadowed_patterns(&["^front", "^front door$"]), vec![pair("^front door$", "^front")]);
+        assert_eq!(shadowed_patterns(&["do+r", "^door$"]), vec![pair("^door$", "do+r")]);
+        assert_eq!(shadowed_patterns(&["^back door$", "^front door$"]), vec![]);
+    }
+
+    #[test]
+    fn unanchored_literal_shadowed() {
+        assert_eq!(shadowed_patterns(&["door", "front door"]), vec![pair("front door", "door")]);
+        assert_eq!(shadowed_patterns(&["door", "^door"]), vec![pair("^door", "door")]);
+    }
+
+    #[test]
+    fn unanchored_literal_not_shadowed() {
+        // the earlier patterns only match some of the names the later ones match
+        assert_eq!(shadowed_patterns(&["^door$", "door"]), vec![]);
+        assert_eq!(shadowed_patterns(&["^door", "door"]), vec![]);
+        assert_eq!(shadowed_patterns(&["do+r", "door"]), vec![]);
+        assert_eq!(shadowed_patterns(&["front door", "door"]), vec![]);
     }
 
 }
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::{ArmingModes, SensorPattern, SensorRuleKey};
+use crate::notifier::{self, Destinations};
+use crate::sensors::{PayloadFieldName, SensorValue};
+use crate::{ProtectedSharedState, SharedConfig};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Topic, sensor pattern, payload field and rule key, None for the messages of exact values.
+/// The patterns matching the same topic have their own pending messages
+type PendingKey = (String, SensorPattern, PayloadFieldName, Option<SensorRuleKey>);
+
+struct PendingNotification {
+    /// Value of the field before the change, None for the rules
+    from_value: Option<SensorValue>,
+    deadline: Instant,
+    message: String,
+    destinations: Destinations,
+    repeat_interval: Option<u64>,
+
+    /// Arming modes of the message, checked again when it is due
+    modes: Option<ArmingModes>
+}
+
+/// State messages waiting for the value to hold during their debounce time, they are lost when the bot is restarted
+#[derive(Default)]
+pub struct Debounced {
+    pending: HashMap<PendingKey, PendingNotification>
+}
+
+impl Debounced {
+
+    /// Holds the message of an exact value, `from_value` is the value of the field before the change
+    #[allow(clippy::too_many_arguments)]
+    pub fn hold_value(&mut self, topic: &str, pattern: &str, field_name: &str, from_value: Option<SensorValue>, debounce: u64, message: String, destinations: Destinations, repeat_interval: Option<u64>, modes: Option<ArmingModes>) {
+        self.pending.insert((topic.to_owned(), pattern.to_owned(), field_name.to_owned(), None), PendingNotification {
+            from_value,
+            deadline: Instant::now() + Duration::from_secs(debounce),
+            message,
+            destinations,
+            repeat_interval,
+            modes
+        });
+    }
+
+    /// Holds the message of a rule, it is sent if the rule is still met once the debounce time is elapsed
+    #[allow(clippy::too_many_arguments)]
+    pub fn hold_rule(&mut self, topic: &str, pattern: &str, field_name: &str, rule_key: &str, debounce: u64, message: String, destinations: Destinations, repeat_interval: Option<u64>, modes: Option<ArmingModes>) {
+        self.pending.insert((topic.to_owned(), pattern.to_owned(), field_name.to_owned(), Some(rule_key.to_owned())), PendingNotification {
+            from_value: None,
+            deadline: Instant::now() + Duration::from_secs(debounce),
+            message,
+            destinations,
+            repeat_interval,
+            modes
+        });
+    }
+
+    /// Cancels the pending message of an exact value when the field value changes
+    /// returns: the value of the field before the pending change, if a message was pending
+    pub fn cancel_value(&mut self, topic: &str, pattern: &str, field_name: &str) -> Option<Option<SensorValue>> {
+        self.pending.remove(&(topic.to_owned(), pattern.to_owned(), field_name.to_owned(), None)).map(|pending| pending.from_value)
+    }
+
+    pub fn cancel_rule(&mut self, topic: &str, pattern: &str, field_name: &str, rule_key: &str) -> bool {
+        self.pending.remove(&(topic.to_owned(), pattern.to_owned(), field_name.to_owned(), Some(rule_key.to_owned()))).is_some()
+    }
+
+    /// Cancels all the pending messages of a field, whatever their pattern
+    pub fn cancel_field(&mut self, topic: &str, field_name: &str) {
+        self.pending.retain(|(pending_topic, _, pending_field_name, _), _| pending_topic != topic || pending_field_name != field_name);
+    }
+
+    // returns: the topics and messages whose debounce time is elapsed
+    fn due(&mut self) -> Vec<(String, PendingNotification)> {
+        let now = Instant::now();
+        let due_keys: Vec<PendingKey> = self.pending.iter().filter(|(_, pending)| pending.deadline <= now).map(|(key, _)| key.clone()).collect();
+        due_keys.into_iter().filter_map(|key| {
+            let pending = self.pending.remove(&key)?;
+            Some((key.0, pending))
+        }).collect()
+    }
+
+}
+
+/// Sends the state messages whose value held during their debounce time
+pub async fn run(shared_config: SharedConfig, shared_state: ProtectedSharedState) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let config = shared_config.read().await.clone();
+        let due_notifications = {
+            let mut locked_shared_state = shared_state.lock().await;
+            let due_notifications = locked_shared_state.debounced.due();
+
+            // notifications may have been disabled, the sensor muted or the alarm mode changed in the meantime
+            let notifications_enabled = locked_shared_state.notifications_enabled;
+            due_notifications.into_iter().filter(|(topic, pending)| {
+                let muted = locked_shared_state.prev_sensors_data.get(topic).is_some_and(|prev_sensor_data| prev_sensor_data.is_muted());
+                let active = config.is_active_in_mode(pending.modes.as_ref(), &locked_shared_state.arming_mode);
+                if !notifications_enabled || muted || !active {
+                    log::debug!("debounced message for {} dropped: {}", topic, pending.message);
+                }
+                notifications_enabled && !muted && active
+            }).map(|(_, pending)| pending).collect::<Vec<PendingNotification>>()
+        };
+
+        for pending in due_notifications {
+            notifier::notify(&config, &shared_state, pending.message, &pending.destinations, pending.repeat_interval).await;
+        }
+    }
+}
@@ -5,23 +5,84 @@ pub mod sensors;
 pub mod config;
 pub mod mqtt;
 pub mod log_level;
+pub mod tls;
+pub mod watchdog;
+pub mod rules;
+pub mod regex_map;
+pub mod ordered_map;
+pub mod topic_filter;
+pub mod template;
+pub mod alerts;
+pub mod schedule;
+pub mod debounce;
+pub mod outbox;
+pub mod notifier;
+pub mod sinks;
+pub mod smtp;
 
+use std::path::Path;
 use std::sync::Arc;
-use sensors::PrevSensorsData;
-use tokio::sync::Mutex;
+use sensors::{DataFile,PrevSensorsData};
+use tokio::sync::{Mutex,RwLock};
+use config::Config;
 
 pub struct SharedState {
     pub prev_sensors_data: PrevSensorsData,
-    pub notifications_enabled: bool
+    pub notifications_enabled: bool,
+    pub arming_mode: config::ArmingMode,
+    pub alerts: alerts::Alerts,
+    pub debounced: debounce::Debounced,
+    pub outbox: outbox::Outbox,
+
+    /// Client of the current MQTT broker connection, used by the mqtt sinks
+    pub mqtt_client: Option<rumqttc::AsyncClient>
 }
 
 impl Default for SharedState {
     fn default() -> Self {
         Self {
             prev_sensors_data: PrevSensorsData::new(),
-            notifications_enabled: false
+            notifications_enabled: false,
+            arming_mode: config::DISARMED_MODE.to_owned(),
+            alerts: Default::default(),
+            debounced: Default::default(),
+            outbox: Default::default(),
+            mqtt_client: None
+        }
+    }
+}
+
+impl SharedState {
+
+    /// Saves the sensors data file and the outbox file, each one is saved even if the other one fails
+    pub fn save_to_file<S: AsRef<Path>>(&self, file_path: S) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+
+        let data_file = DataFile {
+            notifications_enabled: Some(self.notifications_enabled),
+            arming_mode: Some(self.arming_mode.clone()),
+            sensors: &self.prev_sensors_data
+        };
+        if let Err(save_error) = data_file.save_to_file(&file_path) {
+            errors.push(format!("failed to save sensors data to file: {save_error}"));
+        }
+
+        if let Err(save_error) = self.outbox.save_to_file(outbox::file_path(&file_path)) {
+            errors.push(format!("failed to save notifications queue to file: {save_error}"));
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors)
         }
     }
+
 }
 
 pub type ProtectedSharedState = Arc<Mutex<SharedState>>;
+
+/// The live configuration, replaced as a whole when it is reloaded
+pub type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
+/// Requests a configuration reload, with the source of the request for the logs
+pub type ReloadRequestSender = tokio::sync::mpsc::UnboundedSender<&'static str>;
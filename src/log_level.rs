@@ -3,19 +3,14 @@ use clap::ValueEnum;
 use serde::Deserialize;
 use strum::Display;
 
-#[derive(Copy, Clone, Display, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[derive(Copy, Clone, Display, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum LogLevel {
     Off,
     Error,
     Warn,
+    #[default]
     Info,
     Debug,
     Trace,
 }
-
-impl Default for LogLevel {
-    fn default() -> Self {
-        Self::Info
-    }
-}
@@ -1,15 +1,14 @@
 
-use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex,RwLock};
 use tokio::signal::unix::{signal,SignalKind};
 use teloxide::types::ChatId;
 use clap::Parser;
-use telegram_alarm_bot::{config,mqtt,sensors,telegram};
-use config::Config;
-use telegram::SharedBot;
-use sensors::PrevSensorsData;
-use telegram_alarm_bot::{SharedState,ProtectedSharedState};
+use telegram_alarm_bot::{alerts,config,debounce,mqtt,outbox,schedule,sensors,sinks,telegram,watchdog};
+use config::{Config,StartupNotifications};
+use sensors::DataFile;
+use sinks::Sink;
+use telegram_alarm_bot::{SharedState,ProtectedSharedState,SharedConfig};
 use telegram_alarm_bot::log_level::LogLevel;
 
 #[derive(Parser)]
@@ -23,7 +22,7 @@ struct Cli {
     #[clap(short = 'i', long, action)]
     chat_id_discovery: bool,
 
-    /// Start in test mode. The notifications are sent in the admin chats instead of notification chats
+    /// Start in test mode. The notifications are sent in the admin chats instead of notification chats and the other sinks print them
     #[clap(short, long, action)]
     test_mode: bool,
 
@@ -36,61 +35,222 @@ struct Cli {
 }
 
 
+const AUTOSAVE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 async fn terminate(source: &str, shared_state: ProtectedSharedState, config: &Config) -> ! {
     log::info!("received {}, terminating", source);
 
     let locked_shared_data = shared_state.lock().await;
 
-    if let Err(save_error) = locked_shared_data.prev_sensors_data.save_to_file(&config.sensors_data_file) {
-        log::info!("failed to save sensors data to file: {}", save_error);
+    if let Err(save_errors) = locked_shared_data.save_to_file(&config.sensors_data_file) {
+        for save_error in save_errors {
+            log::info!("{}", save_error);
+        }
     }
 
     std::process::exit(0);
 }
 
-async fn notify_start(shared_bot: &SharedBot, notification_chat_ids: &Vec<ChatId>) {
-    log::info!("bot started");
-    let locked_bot = shared_bot.lock().await;
-    for chat_id in notification_chat_ids {
-        telegram::shared_bot_send_message(&locked_bot, chat_id, "Started").await;
+// the interval is read from the live config so that a reload changes it, 0 disables the autosave
+async fn autosave(shared_config: SharedConfig, shared_state: ProtectedSharedState) {
+    let mut interval = tokio::time::interval(AUTOSAVE_CHECK_INTERVAL);
+    let mut last_save = tokio::time::Instant::now();
+
+    loop {
+        interval.tick().await;
+
+        let config = shared_config.read().await.clone();
+        if config.autosave_interval == 0 || last_save.elapsed() < std::time::Duration::from_secs(config.autosave_interval) {
+            continue;
+        }
+        last_save = tokio::time::Instant::now();
+
+        let locked_shared_data = shared_state.lock().await;
+        match locked_shared_data.save_to_file(&config.sensors_data_file) {
+            Ok(()) => log::debug!("saved sensors data to file {:?}", config.sensors_data_file),
+            Err(save_errors) => for save_error in save_errors {
+                log::error!("{}", save_error);
+            }
+        }
     }
 }
 
-async fn load_prev_sensors_data<S: AsRef<Path> + std::fmt::Debug>(prev_sensors_data_file_path: S, shared_state: &ProtectedSharedState) {
-    match PrevSensorsData::load_from_file(&prev_sensors_data_file_path) {
-        Ok(prev_sensors_data_from_file) => {
-            let mut shared_state_locked = shared_state.lock().await;
-            log::info!("loaded prev sensors data from file {:?}", prev_sensors_data_file_path);
-            shared_state_locked.prev_sensors_data = prev_sensors_data_from_file;
+async fn notify_start(shared_state: &ProtectedSharedState, notification_chat_ids: &[ChatId]) {
+    log::info!("bot started");
+    shared_state.lock().await.outbox.push(notification_chat_ids, "Started", None);
+}
+
+// the arming mode is always restored, as long as it is still defined in the config
+async fn load_sensors_data(config: &Config, shared_state: &ProtectedSharedState) {
+    let sensors_data_file_path = &config.sensors_data_file;
+    let startup_notifications = config.startup_notifications;
+    let mut shared_state_locked = shared_state.lock().await;
+
+    match DataFile::load_from_file(sensors_data_file_path) {
+        Ok(data_file) => {
+            log::info!("loaded sensors data from file {:?}", sensors_data_file_path);
+            shared_state_locked.prev_sensors_data = data_file.sensors;
+            if startup_notifications == StartupNotifications::Restore {
+                shared_state_locked.notifications_enabled = data_file.notifications_enabled.unwrap_or_default();
+            }
+            match data_file.arming_mode {
+                Some(arming_mode) if config.is_arming_mode(&arming_mode) => shared_state_locked.arming_mode = arming_mode,
+                Some(arming_mode) => log::warn!("saved arming mode {:?} is not defined anymore, disarming", arming_mode),
+                None => {}
+            }
         },
         Err(sensors::DataFileLoadError::IOError(load_io_error)) if load_io_error.kind() == std::io::ErrorKind::NotFound =>
-            log::info!("prev sensors data file {:?} does not exist", prev_sensors_data_file_path),
+            log::info!("sensors data file {:?} does not exist", sensors_data_file_path),
         Err(load_error) => {
-            log::error!("prev sensors data load error: {}", load_error);
+            log::error!("sensors data load error: {}", load_error);
         }
     };
+
+    let outbox_file_path = outbox::file_path(sensors_data_file_path);
+    match outbox::Outbox::load_from_file(&outbox_file_path) {
+        Ok(outbox) => {
+            if !outbox.is_empty() {
+                log::info!("loaded {} notifications waiting to be sent from file {:?}", outbox.len(), outbox_file_path);
+            }
+            shared_state_locked.outbox = outbox;
+        },
+        Err(sensors::DataFileLoadError::IOError(load_io_error)) if load_io_error.kind() == std::io::ErrorKind::NotFound => {},
+        Err(load_error) => log::error!("notifications queue load error: {}", load_error)
+    }
+
+    if startup_notifications == StartupNotifications::Enabled {
+        shared_state_locked.notifications_enabled = true;
+    }
+
+    log::info!("notifications {}", if shared_state_locked.notifications_enabled { "enabled" } else { "disabled" });
+    log::info!("arming mode: {}", shared_state_locked.arming_mode);
 }
 
-async fn bot(config: &Config) {
+fn apply_cli_options(config: &mut Config, cli: &Cli) -> Result<(), String> {
+    if let Some(log_level) = cli.log_level {
+        config.log_level = log_level;
+    }
+
+    if cli.test_mode {
+        match &config.telegram.admin_chat_ids {
+            Some(admin_chat_ids) => {
+                config.telegram.notification_chat_ids = admin_chat_ids.clone();
+                for group in config.telegram.recipient_groups.values_mut() {
+                    group.chat_ids = admin_chat_ids.clone();
+                }
+                for sink in config.sinks.values_mut() {
+                    *sink = Sink::Stdout;
+                }
+            },
+            None => return Err("admin chat IDs have not been defined".to_owned())
+        }
+    }
+
+    Ok(())
+}
+
+fn reload_config(cli: &Cli) -> Result<Config, Vec<String>> {
+    let mut config = Config::load_from_file(&cli.config_file)
+        .map_err(|load_error| vec![format!("failed to load {}: {}", cli.config_file, load_error)])?;
+
+    let errors = config.errors();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    apply_cli_options(&mut config, cli).map_err(|error| vec![error])?;
+
+    Ok(config)
+}
+
+async fn reload(source: &str, cli: &Cli, shared_config: &SharedConfig, mqtt_connection: &mut mqtt::Connection, shared_state: &ProtectedSharedState) {
+    log::info!("received {}, reloading configuration", source);
+
+    let config = shared_config.read().await.clone();
+
+    let (message, notified_config) = match reload_config(cli) {
+        Ok(mut new_config) => {
+            let changes = config.changes(&new_config);
+
+            if config.mqtt_broker != new_config.mqtt_broker {
+                *mqtt_connection = mqtt::init(&new_config).await;
+                shared_state.lock().await.mqtt_client = Some(mqtt_connection.client().clone());
+            } else {
+                mqtt::update_subscriptions(mqtt_connection, &config, &new_config);
+            }
+
+            // the files are kept until the restart, the queue would otherwise be left in the previous outbox file
+            new_config.sensors_data_file = config.sensors_data_file.clone();
+            let new_config = Arc::new(new_config);
+            *shared_config.write().await = new_config.clone();
+
+            let message = if changes.is_empty() {
+                "Configuration reloaded, no changes".to_owned()
+            } else {
+                format!("Configuration reloaded, changes:\n{}", changes.iter().map(|change| format!("• {change}")).collect::<Vec<String>>().join("\n"))
+            };
+            (message, new_config)
+        },
+        Err(errors) => {
+            let message = format!("Configuration reload rejected:\n{}", errors.iter().map(|error| format!("• {error}")).collect::<Vec<String>>().join("\n"));
+            (message, config)
+        }
+    };
+
+    log::info!("{}", message);
+    telegram::notify_admins(&notified_config, shared_state, telegram::escape_html(&message).as_str()).await;
+}
+
+async fn bot(config: Config, cli: &Cli) {
+    let shared_config: SharedConfig = Arc::new(RwLock::new(Arc::new(config)));
+    let config = shared_config.read().await.clone();
+
     pretty_env_logger::formatted_builder().parse_filters(config.log_level.to_string().as_str()).init();
 
     let mut sigterm_stream = signal(SignalKind::terminate()).expect("failed to setup termination handler");
+    let mut sighup_stream = signal(SignalKind::hangup()).expect("failed to setup reload handler");
+    let (reload_sender, mut reload_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    let sighup_reload_sender = reload_sender.clone();
+    tokio::spawn(async move {
+        while sighup_stream.recv().await.is_some() {
+            if sighup_reload_sender.send("SIGHUP").is_err() {
+                break;
+            }
+        }
+    });
 
     let shared_state = Arc::new(Mutex::new(SharedState::default()));
 
-    load_prev_sensors_data(&config.sensors_data_file, &shared_state).await;
+    load_sensors_data(&config, &shared_state).await;
+
+    let shared_bot = telegram::start_repl(shared_config.clone(), shared_state.clone(), reload_sender).await;
+
+    let mut mqtt_connection = mqtt::init(&config).await;
+    shared_state.lock().await.mqtt_client = Some(mqtt_connection.client().clone());
 
-    let shared_bot = telegram::start_repl(&config.telegram, shared_state.clone()).await;
+    notify_start(&shared_state, &config.telegram.notification_chat_ids).await;
 
-    let mut mqtt_event_loop = mqtt::init(config).await;
+    tokio::spawn(watchdog::run(shared_config.clone(), shared_state.clone()));
+    tokio::spawn(alerts::run(shared_state.clone()));
+    tokio::spawn(debounce::run(shared_config.clone(), shared_state.clone()));
+    tokio::spawn(outbox::run(shared_config.clone(), shared_bot.clone(), shared_state.clone()));
+    tokio::spawn(schedule::run(shared_config.clone(), shared_state.clone()));
 
-    notify_start(&shared_bot, &config.telegram.notification_chat_ids).await;
+    tokio::spawn(autosave(shared_config.clone(), shared_state.clone()));
 
     loop {
+        let config = shared_config.read().await.clone();
         tokio::select! {
-            () = mqtt::handle_events(&mut mqtt_event_loop, config, &shared_bot, &shared_state) => {},
-            Ok(_) = tokio::signal::ctrl_c() => terminate("Ctrl-C", shared_state, config).await,
-            Some(_) = sigterm_stream.recv() => terminate("SIGTERM", shared_state, config).await
+            () = mqtt::handle_events(&mut mqtt_connection, &config, &shared_state) => {},
+            Ok(_) = tokio::signal::ctrl_c() => terminate("Ctrl-C", shared_state, &config).await,
+            Some(_) = sigterm_stream.recv() => terminate("SIGTERM", shared_state, &config).await
+        }
+
+        // reloads are applied between two MQTT events so that the processing of an event is never interrupted,
+        // the keep alive pings make sure they wait at most keep_alive seconds
+        while let Ok(source) = reload_receiver.try_recv() {
+            reload(source, cli, &shared_config, &mut mqtt_connection, &shared_state).await;
         }
     }
 }
@@ -107,7 +267,12 @@ fn check_config(config: &Config, check_only: &bool) {
     if *check_only { println!("Checking config...") }
 
     match config.check() {
-        true => if *check_only { println!("OK") },
+        true => if *check_only {
+            for warning in config.warnings() {
+                println!("Warning: {warning}");
+            }
+            println!("OK")
+        },
         false => std::process::exit(1)
     }
 }
@@ -115,28 +280,26 @@ fn check_config(config: &Config, check_only: &bool) {
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    let mut config = config::Config::load_from_file(&cli.config_file).expect("config load error");
+    let mut config = match config::Config::load_from_file(&cli.config_file) {
+        Ok(config) => config,
+        Err(load_error) => {
+            eprintln!("Error: failed to load {}: {}", cli.config_file, load_error);
+            std::process::exit(1);
+        }
+    };
 
     check_config(&config, &cli.check_only);
 
     if cli.chat_id_discovery {
         chat_id_discovery(&config.telegram).await;
     } else {
-        if let Some(log_level) = cli.log_level {
-            config.log_level = log_level;
-        }
-
-        if cli.test_mode {
-            if let Some(admin_chat_ids) = &config.telegram.admin_chat_ids {
-                config.telegram.notification_chat_ids = admin_chat_ids.clone();
-            } else {
-                eprintln!("Error: admin chat IDs have not been defined");
-                std::process::exit(1);
-            }
+        if let Err(error) = apply_cli_options(&mut config, &cli) {
+            eprintln!("Error: {error}");
+            std::process::exit(1);
         }
 
         if !cli.check_only {
-            bot(&config).await;
+            bot(config, &cli).await;
         }
     }
 }
@@ -1,51 +1,189 @@
 
-use rumqttc::{MqttOptions, AsyncClient, QoS, Event, Packet, EventLoop};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use compound_duration::format_dhms;
+use rumqttc::{MqttOptions, AsyncClient, QoS, Event, Packet, EventLoop, Transport};
 use thiserror::Error;
 
 use crate::config;
+use crate::debounce;
+use crate::notifier::{self, Destinations};
 
+use crate::rules;
 use crate::sensors;
+use crate::template::{self, Template};
+use crate::time::Timestamp;
+use crate::tls;
 use crate::config::Config;
-use crate::{ProtectedSharedState, telegram::{SharedBot, self}};
+use crate::{ProtectedSharedState, SharedState, telegram};
 
-pub async fn init(config: &Config) -> EventLoop {
-    let mut mqtt_options = MqttOptions::new("telegram-alarm-bot", "localhost", 1883);
-    mqtt_options.set_keep_alive(std::time::Duration::from_secs(5));
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+pub struct Connection {
+    client: AsyncClient,
+    event_loop: EventLoop,
+    reconnect_delay: Duration,
+    disconnected_since: Option<Instant>,
+    disconnection_notified: bool
+}
+
+pub async fn init(config: &Config) -> Connection {
+    let broker = &config.mqtt_broker;
+
+    let mut mqtt_options = MqttOptions::new(&broker.client_id, &broker.hostname, broker.port);
+    mqtt_options.set_keep_alive(Duration::from_secs(broker.keep_alive));
+    mqtt_options.set_clean_session(broker.clean_session);
+
+    if let Some((username, password)) = broker.credentials() {
+        mqtt_options.set_credentials(username, password);
+    }
+
+    if let Some(tls) = &broker.tls {
+        let tls_config = tls::load_client_config(tls).expect("failed to load MQTT TLS configuration");
+        mqtt_options.set_transport(Transport::tls_with_config(tls_config.into()));
+    }
 
     let (client, event_loop) = AsyncClient::new(mqtt_options, 10);
 
-    for subscribe_pattern in config.mqtt_subscribe_patterns() {
-        client.subscribe(subscribe_pattern, QoS::AtMostOnce).await.unwrap();
+    Connection {
+        client,
+        event_loop,
+        reconnect_delay: RECONNECT_DELAY_MIN,
+        disconnected_since: None,
+        disconnection_notified: false
+    }
+}
+
+impl Connection {
+
+    pub fn client(&self) -> &AsyncClient {
+        &self.client
+    }
+
+}
+
+// subscriptions are issued from a separate task since the requests channel is only drained while the event loop is polled
+fn subscribe(client: &AsyncClient, config: &Config) {
+    let client = client.clone();
+    let subscribe_patterns = config.mqtt_subscribe_patterns();
+    tokio::spawn(async move {
+        for subscribe_pattern in subscribe_patterns {
+            if let Err(subscribe_error) = client.subscribe(&subscribe_pattern, QoS::AtMostOnce).await {
+                log::error!("failed to subscribe to {}: {}", subscribe_pattern, subscribe_error);
+            }
+        }
+    });
+}
+
+/// Subscribes to the topics added in the new configuration and unsubscribes from the removed ones
+pub fn update_subscriptions(connection: &Connection, config: &Config, new_config: &Config) {
+    let client = connection.client.clone();
+    let subscribe_patterns = config.mqtt_subscribe_patterns();
+    let new_subscribe_patterns = new_config.mqtt_subscribe_patterns();
+    tokio::spawn(async move {
+        for subscribe_pattern in subscribe_patterns.iter().filter(|pattern| !new_subscribe_patterns.contains(pattern)) {
+            if let Err(unsubscribe_error) = client.unsubscribe(subscribe_pattern).await {
+                log::error!("failed to unsubscribe from {}: {}", subscribe_pattern, unsubscribe_error);
+            }
+        }
+        for subscribe_pattern in new_subscribe_patterns.iter().filter(|pattern| !subscribe_patterns.contains(pattern)) {
+            if let Err(subscribe_error) = client.subscribe(subscribe_pattern, QoS::AtMostOnce).await {
+                log::error!("failed to subscribe to {}: {}", subscribe_pattern, subscribe_error);
+            }
+        }
+    });
+}
+
+async fn connection_established(connection: &mut Connection, config: &Config, shared_state: &ProtectedSharedState) {
+    log::info!("connected to mqtt broker");
+
+    subscribe(&connection.client, config);
+    connection.reconnect_delay = RECONNECT_DELAY_MIN;
+
+    if let Some(disconnected_since) = connection.disconnected_since.take() {
+        if connection.disconnection_notified {
+            let message = format!("MQTT broker connection restored after {}", format_dhms(disconnected_since.elapsed().as_secs()));
+            telegram::notify_admins(config, shared_state, message.as_str()).await;
+        }
     }
+    connection.disconnection_notified = false;
+}
 
-    event_loop
+async fn connection_error(connection: &mut Connection, config: &Config, shared_state: &ProtectedSharedState) {
+    let disconnected_since = *connection.disconnected_since.get_or_insert_with(Instant::now);
+    let grace_period = Duration::from_secs(config.mqtt_broker.disconnection_grace_period);
+
+    if !connection.disconnection_notified && disconnected_since.elapsed() >= grace_period {
+        let message = format!("MQTT broker connection lost for {}", format_dhms(disconnected_since.elapsed().as_secs()));
+        telegram::notify_admins(config, shared_state, message.as_str()).await;
+        connection.disconnection_notified = true;
+    }
+
+    log::debug!("reconnecting to mqtt broker in {:?}", connection.reconnect_delay);
+    tokio::time::sleep(connection.reconnect_delay).await;
+    connection.reconnect_delay = std::cmp::min(connection.reconnect_delay * 2, RECONNECT_DELAY_MAX);
 }
 
-pub async fn handle_events(event_loop: &mut EventLoop, config: &Config, shared_bot: &SharedBot, shared_state: &ProtectedSharedState) {
+pub async fn handle_events(connection: &mut Connection, config: &Config, shared_state: &ProtectedSharedState) {
 
-    match event_loop.poll().await {
+    match connection.event_loop.poll().await {
+        Ok(Event::Incoming(Packet::ConnAck(_))) => connection_established(connection, config, shared_state).await,
         Ok(Event::Incoming(Packet::Publish(publish))) => {
-            if let Err(error_str) = process_publish_notification(publish, config, shared_bot, shared_state).await {
+            if let Err(error_str) = process_publish_notification(publish, config, shared_state).await {
                 log::error!("Error processing publish notification: {}", error_str);
             }
         },
         Err(mqtt_connection_error) => {
             log::error!("mqtt connection error: {}", mqtt_connection_error);
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            connection_error(connection, config, shared_state).await;
         }
         _ => {}
     }
 
 }
 
-async fn update_prev_sensor_data(shared_state: &ProtectedSharedState, topic: &str, sensor_name: &str, sensor_payload_field_names_and_state_messages: &config::SensorPayloadFieldNameAndStateMessages, sensor_data: &sensors::Data) {
+// returns: messages to send when the sensor battery goes below the configured thresholds
+fn check_low_battery(config: &Config, prev_sensor_data: &mut sensors::PrevData) -> Vec<String> {
+    let mut messages = vec![];
+
+    let thresholds = config.low_battery.match_sensor_name(&prev_sensor_data.name);
+
+    if let (Some(threshold), Some(battery)) = (thresholds.percent, prev_sensor_data.common.battery_value()) {
+        if !prev_sensor_data.common.battery_low && battery < threshold {
+            prev_sensor_data.common.battery_low = true;
+            messages.push(format!("Sensor <b>{}</b> battery low: {}% (threshold {}%)", telegram::escape_html(&prev_sensor_data.name), battery, threshold));
+        } else if prev_sensor_data.common.battery_low && battery >= threshold.saturating_add(config.low_battery.percent_hysteresis) {
+            log::info!("sensor {} battery level recovered: {}%", prev_sensor_data.name, battery);
+            prev_sensor_data.common.battery_low = false;
+        }
+    }
+
+    if let (Some(threshold), Some(voltage)) = (thresholds.voltage, prev_sensor_data.common.voltage_value()) {
+        if !prev_sensor_data.common.voltage_low && voltage < threshold {
+            prev_sensor_data.common.voltage_low = true;
+            messages.push(format!("Sensor <b>{}</b> battery voltage low: {:.3}v (threshold {:.3}v)", telegram::escape_html(&prev_sensor_data.name), voltage, threshold));
+        } else if prev_sensor_data.common.voltage_low && voltage >= threshold + config.low_battery.voltage_hysteresis {
+            log::info!("sensor {} battery voltage recovered: {:.3}v", prev_sensor_data.name, voltage);
+            prev_sensor_data.common.voltage_low = false;
+        }
+    }
+
+    messages
+}
+
+// returns: messages to send about the sensor state (back online to the sensor recipients, low battery) and their destinations
+async fn update_prev_sensor_data(config: &Config, shared_state: &ProtectedSharedState, topic: &str, sensor_name: &str, sensor_recipients: Option<&config::Recipients>, payload_field_names: Vec<&String>, sensor_data: &sensors::Data) -> Vec<(String, Destinations)> {
     let mut locked_shared_state = shared_state.lock().await;
 
     let prev_sensor_data_entry = locked_shared_state.prev_sensors_data.entry(topic.to_string());
 
+    let mut was_offline = false;
+
     let prev_sensor_data = match prev_sensor_data_entry {
         std::collections::hash_map::Entry::Occupied(entry) => {
             let data = entry.into_mut();
+            was_offline = std::mem::take(&mut data.offline);
             data.last_seen_now();
             data
         },
@@ -53,7 +191,7 @@ async fn update_prev_sensor_data(shared_state: &ProtectedSharedState, topic: &st
             entry.insert(sensors::PrevData::new(sensor_name.to_string()))
     };
 
-    for field_name in sensor_payload_field_names_and_state_messages.payload_field_names() {
+    for field_name in payload_field_names {
         if let Some(field_value) = sensor_data.get(field_name) {
             prev_sensor_data.trigger_states.insert(field_name.clone(), field_value.clone());
         }
@@ -82,54 +220,283 @@ async fn update_prev_sensor_data(shared_state: &ProtectedSharedState, topic: &st
             None => {},
         _ => log::error!("got invalid sensor voltage value type")
     };
+
+    let mut messages = vec![];
+
+    if was_offline {
+        log::info!("sensor {} back online", sensor_name);
+        messages.push((format!("Sensor <b>{}</b> is back online", telegram::escape_html(sensor_name)), config.destinations(sensor_recipients, None)));
+    }
+
+    let low_battery_destinations = config.destinations(config.low_battery.recipients.as_ref(), None);
+    messages.extend(check_low_battery(config, prev_sensor_data).into_iter().map(|message| (message, low_battery_destinations.clone())));
+
+    if prev_sensor_data.is_muted() {
+        log::debug!("sensor {} muted, not sending: {:?}", sensor_name, messages);
+        messages.clear();
+    }
+
+    messages
 }
 
 
+/// What a message template is rendered for
+struct MessageContext<'a> {
+    topic: &'a str,
+    sensor_name: &'a str,
+    sensor_name_captures: &'a config::SensorNameCaptures,
+    field_name: &'a str,
+    value: &'a serde_json::Value,
+    sensor_data: &'a sensors::Data
+}
+
+// named captures take precedence over the template variables
+fn template_variable(name: &str, context: &MessageContext, prev_sensor_data: Option<&sensors::PrevData>) -> Option<serde_json::Value> {
+    if let Some(capture) = context.sensor_name_captures.get(name) {
+        return capture.clone().map(serde_json::Value::String);
+    }
+
+    if let Some(payload_field_name) = name.strip_prefix(template::PAYLOAD_VARIABLE_PREFIX) {
+        return context.sensor_data.get(payload_field_name).cloned();
+    }
+
+    match name {
+        "sensor" => Some(context.sensor_name.into()),
+        "topic" => Some(context.topic.into()),
+        "field" => Some(context.field_name.into()),
+        "value" => Some(context.value.clone()),
+        "previous" => prev_sensor_data.and_then(|psd| psd.trigger_states.get(context.field_name)).cloned(),
+        "timestamp" => Some(Timestamp::now().format("%Y-%m-%d %H:%M:%S").to_string().into()),
+        "battery" => prev_sensor_data.and_then(|psd| psd.common.battery_value()).map(Into::into),
+        "voltage" => prev_sensor_data.and_then(|psd| psd.common.voltage_value()).map(|voltage| format!("{voltage:.3}").into()),
+        "last_seen" => prev_sensor_data.map(|psd| psd.time_since_last_seen().to_string().into()),
+        _ => None
+    }
+}
+
+fn render_message(message_template: &Template, context: &MessageContext, prev_sensor_data: Option<&sensors::PrevData>) -> String {
+    message_template.render(|name| template_variable(name, context, prev_sensor_data))
+}
+
+/// State of the publish being processed, shared by the sensor patterns matching it
+#[derive(Default)]
+struct PublishEvaluation {
+    /// Whether the rules evaluated for the publish fired, the same rule of several patterns is evaluated once
+    /// and fires for all of them
+    fired_rules: HashMap<(sensors::PayloadFieldName, config::SensorRuleKey), bool>,
+
+    /// Fields whose transition has been recorded for the flap detection, a transition is counted once whatever
+    /// the number of patterns
+    flap_transitions: HashSet<sensors::PayloadFieldName>
+}
+
+// returns: the rules which fired
+fn evaluate_rules<'a>(prev_sensor_data: &mut sensors::PrevData, sensor_field_name: &str, state_messages: &'a config::SensorStateMessages, value: f64, evaluation: &mut PublishEvaluation) -> Vec<&'a config::SensorRule> {
+    let field_rule_states = prev_sensor_data.rule_states.entry(sensor_field_name.to_string()).or_default();
+
+    state_messages.rules().iter().filter(|sensor_rule| {
+        *evaluation.fired_rules.entry((sensor_field_name.to_string(), sensor_rule.key.clone())).or_insert_with(|| {
+            let rule_state = field_rule_states.entry(sensor_rule.key.clone()).or_default();
+            let fired = sensor_rule.rule.evaluate(value, rule_state);
+            if fired {
+                log::debug!("rule {:?} fired for field {} of sensor {} with value {}", sensor_rule.key, sensor_field_name, prev_sensor_data.name, value);
+            }
+            fired
+        })
+    }).collect()
+}
+
 #[derive(Debug, Error)]
 pub enum PublishNotificationProcessingError {
-    #[error("regex error")]
-    RegexError(regex::Error),
-    #[error("deserialization error")]
-    DeserializationError(serde_json::Error)
+    #[error("invalid UTF-8 payload")]
+    Utf8Error(std::str::Utf8Error)
 }
 
-async fn process_publish_notification(publish: rumqttc::Publish, config: &Config, shared_bot: &SharedBot, shared_state: &ProtectedSharedState) -> Result<(), PublishNotificationProcessingError> {
+// returns: the message to send when the field starts flapping, its pending debounced messages are cancelled
+fn record_flap_transition(flap_detection: &config::FlapDetection, debounced: &mut debounce::Debounced, topic: &str, prev_sensor_data: &mut sensors::PrevData, field_name: &str) -> Option<String> {
+    let flap_state = prev_sensor_data.flap_states.entry(field_name.to_string()).or_default();
 
-    log::debug!("got mqtt pushblish notification - topic: {}, payload: {:?}", publish.topic, publish.payload);
+    if !flap_state.record_transition(flap_detection.max_transitions, flap_detection.window) {
+        return None;
+    }
 
-    let payload_string = String::from_utf8_lossy(&publish.payload).to_string();
-    let sensor_data: sensors::Data = serde_json::from_str(&payload_string).map_err(PublishNotificationProcessingError::DeserializationError)?;
+    log::info!("field {} of sensor {} is flapping", field_name, prev_sensor_data.name);
+    debounced.cancel_field(topic, field_name);
 
-    if let Some((sensor_name, sensor_name_captures, sensor_payload_field_names_and_state_messages)) =
-            config.mqtt_topics.match_topic(&publish.topic).map_err(PublishNotificationProcessingError::RegexError)? {
-        for (sensor_field_name, state_messages) in sensor_payload_field_names_and_state_messages.iter() {
-            if let Some(sensor_value) = sensor_data.get(sensor_field_name) {
-                if let Some(message_template) = state_messages.get(sensor_value.to_string().as_str()) {
+    Some(format!("Sensor <b>{}</b> is flapping: {} changed {} times in {}, its messages are suppressed until it is stable",
+        telegram::escape_html(&prev_sensor_data.name),
+        telegram::escape_html(field_name),
+        flap_state.transitions_count(),
+        format_dhms(flap_detection.window)
+    ))
+}
+
+fn is_flapping(prev_sensor_data: Option<&sensors::PrevData>, field_name: &str) -> bool {
+    prev_sensor_data.and_then(|psd| psd.flap_states.get(field_name)).is_some_and(|flap_state| flap_state.flapping)
+}
+
+async fn process_sensor_match(config: &Config, shared_state: &ProtectedSharedState, topic: &str, sensor_match: &config::SensorMatch<'_>, sensor_data: &sensors::Data, evaluation: &mut PublishEvaluation) {
+    let (sensor_name, sensor_name_captures, sensor_payload_field_names_and_state_messages, pattern) = sensor_match;
 
-                    let locked_shared_state = shared_state.lock().await;
-                    let prev_sensor_data = locked_shared_state.prev_sensors_data.get(&publish.topic);
+    // messages are rendered while the shared state is locked and sent afterwards
+    let mut notifications = vec![];
 
-                    let prev_value = prev_sensor_data.and_then(|psd| psd.trigger_states.get(sensor_field_name));
+    {
+        let mut locked_shared_state = shared_state.lock().await;
+        let SharedState { prev_sensors_data, notifications_enabled, arming_mode, debounced, .. } = &mut *locked_shared_state;
 
-                    if locked_shared_state.notifications_enabled && (prev_value.is_none() || sensor_value != prev_value.unwrap()) {
+        for (sensor_field_name, state_messages) in sensor_payload_field_names_and_state_messages.iter() {
+            if let Some(sensor_value) = sensor_data.get(sensor_field_name) {
+                let context = MessageContext {
+                    topic,
+                    sensor_name,
+                    sensor_name_captures,
+                    field_name: sensor_field_name,
+                    value: sensor_value,
+                    sensor_data
+                };
+
+                let prev_value = prev_sensors_data.get(topic).and_then(|psd| psd.trigger_states.get(sensor_field_name)).cloned();
+                let changed = prev_value.as_ref() != Some(sensor_value);
+
+                // the change is compared to the value before the pending debounced message, going back to it cancels the message
+                let mut from_value = prev_value.clone();
+                let mut returned = false;
+
+                if changed {
+                    if let Some(pending_from_value) = debounced.cancel_value(topic, pattern, sensor_field_name) {
+                        returned = pending_from_value.as_ref() == Some(sensor_value);
+                        if returned {
+                            log::debug!("field {} of sensor {} back to {} within its debounce time, message cancelled", sensor_field_name, sensor_name, sensor_value);
+                        }
+                        from_value = pending_from_value;
+                    }
 
-                        let mut message = message_template.clone();
-                        for (cname, cstr) in &sensor_name_captures {
-                            if let Some(cstr) = cstr {
-                                message.replace_range(0.., message.replace(format!("{{{cname}}}").as_str(), cstr).as_str());
+                    if let (Some(flap_detection), Some(prev_sensor_data), false) = (&config.flap_detection, prev_sensors_data.get_mut(topic), state_messages.is_empty()) {
+                        if prev_value.is_some() && evaluation.flap_transitions.insert(sensor_field_name.clone()) {
+                            if let Some(message) = record_flap_transition(flap_detection, debounced, topic, prev_sensor_data, sensor_field_name) {
+                                if *notifications_enabled && !prev_sensor_data.is_muted() {
+                                    notifications.push((message, config.destinations(sensor_payload_field_names_and_state_messages.recipients(), None), None));
+                                }
                             }
                         }
+                    }
+                }
+
+                if let Some(state_message) = state_messages.message(sensor_value) {
+                    let prev_sensor_data = prev_sensors_data.get(topic);
 
-                        for chat_id in &config.telegram.notification_chat_ids {
-                            telegram::shared_bot_send_message(&shared_bot.lock().await, chat_id, message.as_str()).await;
+                    let modes = state_message.modes.as_ref().or(sensor_payload_field_names_and_state_messages.modes());
+
+                    let muted = prev_sensor_data.is_some_and(sensors::PrevData::is_muted);
+
+                    let flapping = config.flap_detection.is_some() && is_flapping(prev_sensor_data, sensor_field_name);
+
+                    if *notifications_enabled && !muted && !flapping && config.is_active_in_mode(modes, arming_mode) && changed && !returned {
+
+                        let message = render_message(&state_message.template, &context, prev_sensor_data);
+                        let destinations = config.destinations(state_message.recipients.as_ref().or(sensor_payload_field_names_and_state_messages.recipients()), state_message.sinks.as_ref());
+
+                        match state_message.debounce {
+                            Some(debounce) => {
+                                log::debug!("message for field {} of sensor {} held for {}s", sensor_field_name, sensor_name, debounce);
+                                debounced.hold_value(topic, pattern, sensor_field_name, from_value, debounce, message, destinations, state_message.repeat_interval, modes.cloned());
+                            },
+                            None => notifications.push((message, destinations, state_message.repeat_interval))
                         }
 
                     }
                 }
+
+                if !state_messages.rules().is_empty() {
+                    match rules::numeric_value(sensor_value) {
+                        Some(numeric_value) => {
+                            let prev_sensor_data = prev_sensors_data.entry(topic.to_string())
+                                .or_insert_with(|| sensors::PrevData::new(sensor_name.to_string()));
+
+                            let fired_rules = evaluate_rules(prev_sensor_data, sensor_field_name, state_messages, numeric_value, evaluation);
+
+                            // a debounced rule message is cancelled once its rule is cleared
+                            for sensor_rule in state_messages.rules().iter().filter(|sensor_rule| sensor_rule.message.debounce.is_some()) {
+                                let active = prev_sensor_data.rule_states.get(sensor_field_name)
+                                    .and_then(|field_rule_states| field_rule_states.get(&sensor_rule.key))
+                                    .is_some_and(rules::RuleState::is_active);
+                                if !active && debounced.cancel_rule(topic, pattern, sensor_field_name, &sensor_rule.key) {
+                                    log::debug!("rule {:?} of sensor {} cleared within its debounce time, message cancelled", sensor_rule.key, sensor_name);
+                                }
+                            }
+
+                            // for the rules a transition is a rule firing
+                            if let (Some(flap_detection), false) = (&config.flap_detection, fired_rules.is_empty()) {
+                                if evaluation.flap_transitions.insert(sensor_field_name.clone()) {
+                                    if let Some(message) = record_flap_transition(flap_detection, debounced, topic, prev_sensor_data, sensor_field_name) {
+                                        if *notifications_enabled && !prev_sensor_data.is_muted() {
+                                            notifications.push((message, config.destinations(sensor_payload_field_names_and_state_messages.recipients(), None), None));
+                                        }
+                                    }
+                                }
+                            }
+
+                            let flapping = config.flap_detection.is_some() && is_flapping(Some(prev_sensor_data), sensor_field_name);
+
+                            if *notifications_enabled && !prev_sensor_data.is_muted() && !flapping {
+                                for sensor_rule in fired_rules {
+                                    let modes = sensor_rule.message.modes.as_ref().or(sensor_payload_field_names_and_state_messages.modes());
+                                    if !config.is_active_in_mode(modes, arming_mode) {
+                                        log::debug!("rule {:?} of sensor {} not active in mode {}", sensor_rule.key, sensor_name, arming_mode);
+                                        continue;
+                                    }
+
+                                    let message = render_message(&sensor_rule.message.template, &context, Some(prev_sensor_data));
+                                    let destinations = config.destinations(sensor_rule.message.recipients.as_ref().or(sensor_payload_field_names_and_state_messages.recipients()), sensor_rule.message.sinks.as_ref());
+
+                                    match sensor_rule.message.debounce {
+                                        Some(debounce) => {
+                                            log::debug!("message for rule {:?} of sensor {} held for {}s", sensor_rule.key, sensor_name, debounce);
+                                            debounced.hold_rule(topic, pattern, sensor_field_name, &sensor_rule.key, debounce, message, destinations, sensor_rule.message.repeat_interval, modes.cloned());
+                                        },
+                                        None => notifications.push((message, destinations, sensor_rule.message.repeat_interval))
+                                    }
+                                }
+                            }
+                        },
+                        None => log::warn!("non numeric value for field {} of sensor {}: {}", sensor_field_name, sensor_name, sensor_value)
+                    }
+                }
             }
         }
+    }
 
-        update_prev_sensor_data(shared_state, &publish.topic, &sensor_name, sensor_payload_field_names_and_state_messages, &sensor_data).await;
+    for (message, destinations, repeat_interval) in notifications {
+        notifier::notify(config, shared_state, message, &destinations, repeat_interval).await;
+    }
+}
+
+async fn process_publish_notification(publish: rumqttc::Publish, config: &Config, shared_state: &ProtectedSharedState) -> Result<(), PublishNotificationProcessingError> {
+
+    log::debug!("got mqtt pushblish notification - topic: {}, payload: {:?}", publish.topic, publish.payload);
+
+    let payload_str = std::str::from_utf8(&publish.payload).map_err(PublishNotificationProcessingError::Utf8Error)?;
+    let sensor_data = sensors::Data::from_payload(payload_str);
+
+    let sensor_matches = config.mqtt_topics.match_topic(&publish.topic, config.match_all_sensor_patterns);
+
+    if let Some((sensor_name, _, first_sensor_payload_field_names_and_state_messages, _)) = sensor_matches.first() {
+        let mut evaluation = PublishEvaluation::default();
+        for sensor_match in &sensor_matches {
+            process_sensor_match(config, shared_state, &publish.topic, sensor_match, &sensor_data, &mut evaluation).await;
+        }
+
+        let payload_field_names = sensor_matches.iter().flat_map(|(_, _, sensor_payload_field_names_and_state_messages, _)| {
+            sensor_payload_field_names_and_state_messages.payload_field_names()
+        }).collect();
+
+        let sensor_recipients = first_sensor_payload_field_names_and_state_messages.recipients();
+        let sensor_messages = update_prev_sensor_data(config, shared_state, &publish.topic, sensor_name, sensor_recipients, payload_field_names, &sensor_data).await;
+
+        for (message, destinations) in sensor_messages {
+            notifier::notify(config, shared_state, message, &destinations, None).await;
+        }
 
     }
 
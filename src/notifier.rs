@@ -0,0 +1,132 @@
+use std::future::Future;
+use rumqttc::AsyncClient;
+use teloxide::types::ChatId;
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::sinks::SinkNotifier;
+use crate::smtp::SmtpError;
+use crate::time::Timestamp;
+use crate::{alerts, telegram, ProtectedSharedState};
+
+/// Name of the built-in sink of the Telegram chats, the other sinks are defined in the config
+pub const TELEGRAM_SINK: &str = "telegram";
+
+pub type SinkName = String;
+pub type SinkNames = Vec<SinkName>;
+
+/// Message emitted by the rules, in the Telegram HTML format
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub message: String,
+    pub timestamp: Timestamp
+}
+
+impl Notification {
+
+    pub fn new(message: String) -> Self {
+        Self { message, timestamp: Timestamp::now() }
+    }
+
+    /// The message without its HTML tags, for the sinks which do not support them
+    pub fn text(&self) -> String {
+        telegram::html_to_plain_text(&self.message)
+    }
+
+    /// First line of the text, used as title or subject by the sinks which have one
+    pub fn title(&self) -> String {
+        self.text().lines().next().unwrap_or_default().to_owned()
+    }
+
+    /// Timestamp and text on a single line, for the logs and files
+    pub fn line(&self) -> String {
+        format!("{} {}\n", self.timestamp.format("%Y-%m-%d %H:%M:%S"), self.text().replace('\n', " | "))
+    }
+
+}
+
+#[derive(Debug, Error)]
+pub enum NotifierError {
+    #[error("HTTP error: {0}")]
+    HttpError(reqwest::Error),
+    #[error("SMTP error: {0}")]
+    SmtpError(SmtpError),
+    #[error("IO error: {0}")]
+    IOError(std::io::Error),
+    #[error("MQTT error: {0}")]
+    MqttError(rumqttc::ClientError),
+    #[error("MQTT client not initialized")]
+    MqttNotConnected,
+    #[error("timeout")]
+    Timeout
+}
+
+/// Backend a notification can be delivered through
+pub trait Notifier {
+    fn notify(&self, notification: &Notification) -> impl Future<Output = Result<(), NotifierError>> + Send;
+}
+
+/// Telegram chats, the notification is queued in the outbox and raised as an alert when it has a repeat interval
+pub struct Telegram<'a> {
+    pub shared_state: &'a ProtectedSharedState,
+    pub chat_ids: &'a [ChatId],
+    pub repeat_interval: Option<u64>
+}
+
+impl Notifier for Telegram<'_> {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        alerts::notify(self.shared_state, notification.message.clone(), self.chat_ids.to_vec(), self.repeat_interval).await;
+        Ok(())
+    }
+}
+
+/// Telegram chats and other sinks a message is sent to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Destinations {
+    pub chat_ids: Vec<ChatId>,
+    pub sinks: SinkNames
+}
+
+impl Destinations {
+
+    pub fn telegram(chat_ids: Vec<ChatId>) -> Self {
+        Self { chat_ids, sinks: vec![] }
+    }
+
+}
+
+/// Sends a notification to sinks in the background, their failures are only logged
+pub fn notify_sinks(config: &Config, sink_names: &[SinkName], notification: &Notification, mqtt_client: Option<&AsyncClient>) {
+    for sink_name in sink_names {
+        let Some(sink) = config.sinks.get(sink_name) else {
+            log::warn!("sink {:?} is not defined anymore", sink_name);
+            continue;
+        };
+
+        let sink_notifier = SinkNotifier { sink: sink.clone(), mqtt_client: mqtt_client.cloned() };
+        let (sink_name, notification) = (sink_name.clone(), notification.clone());
+        tokio::spawn(async move {
+            match sink_notifier.notify(&notification).await {
+                Ok(()) => log::debug!("sent notification to sink {}", sink_name),
+                Err(notify_error) => log::error!("failed to send notification to sink {}: {}", sink_name, notify_error)
+            }
+        });
+    }
+}
+
+/// Sends a message to its Telegram chats and to its other sinks
+pub async fn notify(config: &Config, shared_state: &ProtectedSharedState, message: String, destinations: &Destinations, repeat_interval: Option<u64>) {
+    let notification = Notification::new(message);
+
+    if !destinations.chat_ids.is_empty() {
+        let telegram = Telegram { shared_state, chat_ids: &destinations.chat_ids, repeat_interval };
+        if let Err(notify_error) = telegram.notify(&notification).await {
+            log::error!("failed to queue Telegram notification: {}", notify_error);
+        }
+    }
+
+    if !destinations.sinks.is_empty() {
+        let mqtt_client = shared_state.lock().await.mqtt_client.clone();
+        notify_sinks(config, &destinations.sinks, &notification, mqtt_client.as_ref());
+    }
+}
@@ -0,0 +1,41 @@
+use std::fmt::Display;
+use std::marker::PhantomData;
+use serde::Deserialize;
+use serde::de::{MapAccess, Visitor};
+
+/// Map entries in the order in which they appear in the config file
+pub type OrderedMap<K, V> = Vec<(K, V)>;
+
+struct OrderedMapVisitor<K, V>(PhantomData<(K, V)>);
+
+impl<'de, K, V> Visitor<'de> for OrderedMapVisitor<K, V>
+where
+    K: Deserialize<'de> + PartialEq + Display,
+    V: Deserialize<'de>
+{
+    type Value = OrderedMap<K, V>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut entries: OrderedMap<K, V> = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((key, value)) = map.next_entry::<K, V>()? {
+            if entries.iter().any(|(existing_key, _)| *existing_key == key) {
+                return Err(serde::de::Error::custom(format!("duplicate key: {key}")));
+            }
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+}
+
+pub fn deserialize<'de, D, K, V>(deserializer: D) -> Result<OrderedMap<K, V>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    K: Deserialize<'de> + PartialEq + Display,
+    V: Deserialize<'de>
+{
+    deserializer.deserialize_map(OrderedMapVisitor(PhantomData))
+}
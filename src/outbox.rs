@@ -0,0 +1,708 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use compound_duration::format_dhms;
+use serde::{Serialize, Deserialize};
+use teloxide::{ApiError, RequestError};
+use teloxide::types::ChatId;
+
+use crate::alerts::AlertId;
+use crate::config::{Config, Fallback, RateLimit};
+use crate::notifier::{self, Notification};
+use crate::sensors::{self, DataFileLoadError, DataFileSaveError};
+use crate::time::{LastSeenDuration, Timestamp};
+use crate::{ProtectedSharedState, SharedConfig, SharedState, telegram::{SharedBot, self}};
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+const RETRY_DELAY_MIN: Duration = Duration::from_secs(1);
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(600);
+
+/// Longest message accepted by Telegram
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Room left for the digest header, "<count> notifications since <time>:"
+const DIGEST_HEADER_LENGTH: usize = 64;
+
+/// File of the notifications waiting to be sent, next to the sensors data file
+pub fn file_path<P: AsRef<Path>>(sensors_data_file_path: P) -> PathBuf {
+    sensors::path_with_suffix(sensors_data_file_path, ".outbox")
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl TokenBucket {
+
+    fn full(capacity: f64) -> Self {
+        Self { tokens: capacity, updated: Instant::now() }
+    }
+
+    // refills the bucket at the given rate up to its capacity
+    fn refill(&mut self, capacity: f64, per_second: f64) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * per_second).min(capacity);
+        self.updated = now;
+    }
+
+    fn is_available(&self) -> bool {
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutboundMessage {
+    text: String,
+
+    /// Alert the message belongs to, it is sent with the acknowledgement keyboard.
+    /// Not saved since the alerts are lost when the bot is restarted, the message is then sent without keyboard
+    #[serde(skip)]
+    alert_id: Option<AlertId>,
+
+    /// Time of the event the message is about
+    queued_at: Timestamp,
+
+    /// Whether the message has been sent to the fallback sinks
+    #[serde(default)]
+    forwarded: bool
+}
+
+impl OutboundMessage {
+
+    fn is_delayed(&self, delayed_notification_age: u64) -> bool {
+        LastSeenDuration::new(&self.queued_at).num_seconds() >= delayed_notification_age as i64
+    }
+
+    // the messages sent long after their event tell when it happened
+    fn text(&self, delayed_notification_age: u64) -> String {
+        if !self.is_delayed(delayed_notification_age) {
+            return self.text.clone();
+        }
+        format!("<i>Delayed notification from {}</i>\n{}", self.queued_at.format("%Y-%m-%d %H:%M:%S"), self.text)
+    }
+
+    fn digest_item(&self, delayed_notification_age: u64) -> String {
+        if !self.is_delayed(delayed_notification_age) {
+            return format!("• {}", self.text);
+        }
+        format!("• <i>{}</i> {}", self.queued_at.format("%Y-%m-%d %H:%M:%S"), self.text)
+    }
+
+}
+
+#[derive(Default)]
+struct ChatQueue {
+    messages: VecDeque<OutboundMessage>,
+    bucket: Option<TokenBucket>,
+
+    /// Set when Telegram asks to wait or after a failure, before sending again to the chat
+    retry_at: Option<Instant>,
+
+    /// Consecutive failures to send to the chat, the retry delay doubles with each of them
+    failures: u32,
+
+    /// The first messages of the queue are being sent, they are only removed once Telegram confirmed their delivery
+    in_flight: bool,
+
+    /// Largest digest to send after Telegram rejected a longer one, until a delivery succeeds
+    digest_limit: Option<usize>
+}
+
+/// A message ready to be sent, possibly a digest of several queued messages
+struct Delivery {
+    chat_id: ChatId,
+    text: String,
+    alert_id: Option<AlertId>,
+
+    /// Number of messages of the front of the chat queue which are delivered
+    count: usize
+}
+
+// the alert messages are never part of a digest so that they keep their keyboard, the length is the one of the
+// rendered items, with the time of the delayed messages, in bytes which is more than Telegram counts
+// returns: the number of messages of the front of the queue fitting in a digest
+fn digest_count(messages: &VecDeque<OutboundMessage>, delayed_notification_age: u64) -> usize {
+    let mut count = 0;
+    let mut length = DIGEST_HEADER_LENGTH;
+
+    for message in messages {
+        if message.alert_id.is_some() {
+            break;
+        }
+        // the items are separated by a new line
+        let item_length = message.digest_item(delayed_notification_age).len() + 1;
+        if count > 0 && length + item_length > MAX_MESSAGE_LENGTH {
+            break;
+        }
+        length += item_length;
+        count += 1;
+    }
+
+    count
+}
+
+fn digest_text<'a, I: Iterator<Item = &'a OutboundMessage>>(messages: I, delayed_notification_age: u64) -> String {
+    let messages: Vec<&OutboundMessage> = messages.collect();
+    let oldest = messages.iter().map(|message| *message.queued_at).min();
+    let items = messages.iter().map(|message| message.digest_item(delayed_notification_age)).collect::<Vec<String>>().join("\n");
+    format!("{} notifications since {}:\n{}", messages.len(), oldest.map(|oldest| oldest.format("%H:%M:%S").to_string()).unwrap_or_default(), items)
+}
+
+// saved as a list since the chat IDs cannot be JSON keys
+#[derive(Serialize, Deserialize)]
+struct SavedMessage<M> {
+    chat_id: ChatId,
+    #[serde(flatten)]
+    message: M
+}
+
+/// Telegram outage during which the notifications are also sent to the fallback sinks
+struct FallbackState {
+    /// Time of the first failure
+    failing_since: Timestamp,
+
+    /// Number of notifications sent to the fallback sinks
+    forwarded: usize
+}
+
+/// Notifications waiting to be sent within the rate limits, saved to a file whenever they change so that they are not lost
+#[derive(Default)]
+pub struct Outbox {
+    chats: HashMap<ChatId, ChatQueue>,
+    global_bucket: Option<TokenBucket>,
+
+    /// Consecutive failures to send to Telegram whatever the chat, with the time of the first one
+    telegram_failures: Option<(u32, Timestamp)>,
+
+    fallback: Option<FallbackState>,
+
+    /// Whether the messages changed since they were last saved
+    changed: bool
+}
+
+impl Outbox {
+
+    pub fn push(&mut self, chat_ids: &[ChatId], text: &str, alert_id: Option<AlertId>) {
+        let queued_at = Timestamp::now();
+        for chat_id in chat_ids {
+            self.chats.entry(*chat_id).or_default().messages.push_back(OutboundMessage {
+                text: text.to_owned(),
+                alert_id,
+                queued_at: queued_at.clone(),
+                forwarded: false
+            });
+        }
+        self.changed = true;
+    }
+
+    pub fn len(&self) -> usize {
+        self.chats.values().map(|chat_queue| chat_queue.messages.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chats.values().all(|chat_queue| chat_queue.messages.is_empty())
+    }
+
+    /// Whether a message of the alert is still waiting to be delivered to the chat
+    pub fn is_alert_queued(&self, chat_id: ChatId, alert_id: AlertId) -> bool {
+        self.chats.get(&chat_id).is_some_and(|chat_queue| chat_queue.messages.iter().any(|message| message.alert_id == Some(alert_id)))
+    }
+
+    // returns: the messages which can be sent now, the messages which piled up for a chat are sent as a digest
+    fn next_deliveries<F: Fn(AlertId) -> bool>(&mut self, rate_limit: &RateLimit, delayed_notification_age: u64, is_alert_pending: F) -> Vec<Delivery> {
+        let mut deliveries = vec![];
+        let now = Instant::now();
+
+        let global_capacity = rate_limit.global_per_second as f64;
+        let global_bucket = self.global_bucket.get_or_insert_with(|| TokenBucket::full(global_capacity));
+
+        let chat_capacity = rate_limit.chat_per_minute as f64;
+        let chat_per_second = chat_capacity / 60.0;
+
+        for (chat_id, chat_queue) in self.chats.iter_mut() {
+            if chat_queue.in_flight || chat_queue.retry_at.is_some_and(|retry_at| now < retry_at) {
+                continue;
+            }
+
+            // the alerts acknowledged while their message was waiting are not sent again
+            while chat_queue.messages.front().is_some_and(|message| message.alert_id.is_some_and(|alert_id| !is_alert_pending(alert_id))) {
+                chat_queue.messages.pop_front();
+                self.changed = true;
+            }
+
+            if chat_queue.messages.is_empty() {
+                continue;
+            }
+
+            let chat_bucket = chat_queue.bucket.get_or_insert_with(|| TokenBucket::full(chat_capacity));
+            chat_bucket.refill(chat_capacity, chat_per_second);
+            if !chat_bucket.is_available() {
+                continue;
+            }
+            global_bucket.refill(global_capacity, global_capacity);
+            if !global_bucket.is_available() {
+                break;
+            }
+
+            // the queue backs up when more messages are waiting than can be sent to the chat right now
+            let backed_up = chat_queue.messages.len() >= rate_limit.digest_threshold && chat_queue.messages.len() as f64 > chat_bucket.tokens;
+
+            chat_bucket.take();
+            global_bucket.take();
+
+            let count = match backed_up {
+                true => digest_count(&chat_queue.messages, delayed_notification_age).min(chat_queue.digest_limit.unwrap_or(usize::MAX)).max(1),
+                false => 1
+            };
+
+            let delivery = match count {
+                1 => {
+                    let message = &chat_queue.messages[0];
+                    Delivery { chat_id: *chat_id, text: message.text(delayed_notification_age), alert_id: message.alert_id, count }
+                },
+                count => {
+                    log::info!("sending a digest of {} notifications to chat {}", count, chat_id);
+                    Delivery { chat_id: *chat_id, text: digest_text(chat_queue.messages.iter().take(count), delayed_notification_age), alert_id: None, count }
+                }
+            };
+            chat_queue.in_flight = true;
+            deliveries.push(delivery);
+        }
+
+        // chats which no longer have messages waiting are kept until their bucket is full again
+        self.chats.retain(|_, chat_queue| {
+            if let Some(bucket) = &mut chat_queue.bucket {
+                bucket.refill(chat_capacity, chat_per_second);
+            }
+            !chat_queue.messages.is_empty()
+                || chat_queue.retry_at.is_some_and(|retry_at| now < retry_at)
+                || chat_queue.bucket.as_ref().is_some_and(|bucket| bucket.tokens < chat_capacity)
+        });
+
+        deliveries
+    }
+
+    // removes the messages of the delivery from the front of the chat queue
+    fn remove(&mut self, delivery: &Delivery) {
+        if let Some(chat_queue) = self.chats.get_mut(&delivery.chat_id) {
+            chat_queue.messages.drain(..delivery.count.min(chat_queue.messages.len()));
+            chat_queue.in_flight = false;
+            chat_queue.failures = 0;
+            chat_queue.digest_limit = None;
+            self.changed = true;
+        }
+    }
+
+    // a digest Telegram finds too long is sent again right away as two smaller ones
+    // returns: whether the delivery is split, a single message too long cannot be
+    fn too_long(&mut self, delivery: &Delivery) -> bool {
+        if delivery.count < 2 {
+            return false;
+        }
+        match self.chats.get_mut(&delivery.chat_id) {
+            Some(chat_queue) => {
+                chat_queue.in_flight = false;
+                chat_queue.digest_limit = Some(delivery.count / 2);
+                true
+            },
+            None => false
+        }
+    }
+
+    // the messages stay at the front of the chat queue
+    fn retry_later(&mut self, delivery: &Delivery, retry_after: Duration) {
+        if let Some(chat_queue) = self.chats.get_mut(&delivery.chat_id) {
+            chat_queue.in_flight = false;
+            chat_queue.retry_at = Some(Instant::now() + retry_after);
+        }
+    }
+
+    // returns: the delay before sending again to the chat, doubled with each consecutive failure
+    fn failed(&mut self, delivery: &Delivery) -> Duration {
+        let retry_delay = match self.chats.get_mut(&delivery.chat_id) {
+            Some(chat_queue) => {
+                let retry_delay = std::cmp::min(RETRY_DELAY_MIN.saturating_mul(2u32.saturating_pow(chat_queue.failures)), RETRY_DELAY_MAX);
+                chat_queue.failures = chat_queue.failures.saturating_add(1);
+                retry_delay
+            },
+            None => RETRY_DELAY_MIN
+        };
+        self.retry_later(delivery, retry_delay);
+        retry_delay
+    }
+
+    pub fn is_fallback_active(&self) -> bool {
+        self.fallback.is_some()
+    }
+
+    // the failures are counted across chats since they come from Telegram itself rather than from a chat
+    // returns: whether the fallback sinks are used from now on
+    fn telegram_failed(&mut self, fallback: Option<&Fallback>) -> bool {
+        let (failures, failing_since) = self.telegram_failures.get_or_insert_with(|| (0, Timestamp::now()));
+        *failures = failures.saturating_add(1);
+
+        let Some(fallback) = fallback else {
+            return false;
+        };
+        if self.fallback.is_some() || *failures < fallback.failures {
+            return false;
+        }
+
+        self.fallback = Some(FallbackState { failing_since: failing_since.clone(), forwarded: 0 });
+        true
+    }
+
+    // returns: the summary of the outage if the fallback sinks were used
+    fn telegram_succeeded(&mut self) -> Option<String> {
+        let (failures, _) = self.telegram_failures.take()?;
+        let fallback = self.fallback.take()?;
+        Some(format!("Telegram reachable again after {} ({} failed attempts), {} notification(s) sent to the fallback sinks meanwhile",
+            LastSeenDuration::new(&fallback.failing_since),
+            failures,
+            fallback.forwarded
+        ))
+    }
+
+    // the same message queued for several chats is forwarded once
+    // returns: the messages not forwarded yet, oldest first
+    fn take_unforwarded(&mut self) -> Vec<Notification> {
+        let mut notifications: Vec<Notification> = vec![];
+        for message in self.chats.values_mut().flat_map(|chat_queue| chat_queue.messages.iter_mut()).filter(|message| !message.forwarded) {
+            message.forwarded = true;
+            if !notifications.iter().any(|notification| notification.message == message.text && *notification.timestamp == *message.queued_at) {
+                notifications.push(Notification { message: message.text.clone(), timestamp: message.queued_at.clone() });
+            }
+        }
+        notifications.sort_by_key(|notification| *notification.timestamp);
+
+        if let Some(fallback) = &mut self.fallback {
+            fallback.forwarded += notifications.len();
+        }
+        if !notifications.is_empty() {
+            self.changed = true;
+        }
+        notifications
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, file_path: P) -> Result<(), DataFileSaveError> {
+        let messages: Vec<SavedMessage<&OutboundMessage>> = self.chats.iter().flat_map(|(chat_id, chat_queue)| {
+            chat_queue.messages.iter().map(|message| SavedMessage { chat_id: *chat_id, message })
+        }).collect();
+        let messages_json = serde_json::to_string_pretty(&messages).map_err(DataFileSaveError::SerializationError)?;
+
+        // written to a temporary file first like the sensors data file
+        let temp_file_path = sensors::path_with_suffix(&file_path, ".tmp");
+        let mut temp_file = std::fs::File::create(&temp_file_path).map_err(DataFileSaveError::IOError)?;
+        temp_file.write_all(messages_json.as_bytes()).map_err(DataFileSaveError::IOError)?;
+        temp_file.sync_all().map_err(DataFileSaveError::IOError)?;
+        std::fs::rename(temp_file_path, file_path).map_err(DataFileSaveError::IOError)
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(file_path: P) -> Result<Self, DataFileLoadError> {
+        let file = std::fs::File::open(file_path).map_err(DataFileLoadError::IOError)?;
+        let reader = std::io::BufReader::new(file);
+        let messages: Vec<SavedMessage<OutboundMessage>> = serde_json::from_reader(reader).map_err(DataFileLoadError::DeserializationError)?;
+
+        let mut outbox = Self::default();
+        for saved_message in messages {
+            outbox.chats.entry(saved_message.chat_id).or_default().messages.push_back(saved_message.message);
+        }
+        Ok(outbox)
+    }
+
+}
+
+// a failure to save is retried at the next check
+fn save_if_changed(config: &Config, outbox: &mut Outbox) {
+    if !outbox.changed {
+        return;
+    }
+
+    let outbox_file_path = file_path(&config.sensors_data_file);
+    match outbox.save_to_file(&outbox_file_path) {
+        Ok(()) => outbox.changed = false,
+        Err(save_error) => log::error!("failed to save the notifications queue to file {:?}: {}", outbox_file_path, save_error)
+    }
+}
+
+/// Sends the queued notifications within the configured rate limits, they are sent again with an increasing delay until Telegram confirms their delivery.
+/// After several consecutive failures they are also sent to the fallback sinks until Telegram can be reached again
+pub async fn run(shared_config: SharedConfig, shared_bot: SharedBot, shared_state: ProtectedSharedState) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let config = shared_config.read().await.clone();
+
+        let deliveries = {
+            let mut locked_shared_state = shared_state.lock().await;
+            let SharedState { outbox, alerts, mqtt_client, .. } = &mut *locked_shared_state;
+
+            // the notifications queued during an outage are forwarded as soon as they are queued
+            if let (Some(fallback), true) = (&config.telegram.fallback, outbox.is_fallback_active()) {
+                for notification in outbox.take_unforwarded() {
+                    notifier::notify_sinks(&config, &fallback.sinks, &notification, mqtt_client.as_ref());
+                }
+            }
+
+            let deliveries = outbox.next_deliveries(&config.telegram.rate_limit, config.telegram.delayed_notification_age, |alert_id| alerts.is_pending(alert_id));
+            save_if_changed(&config, outbox);
+            deliveries
+        };
+
+        if deliveries.is_empty() {
+            continue;
+        }
+
+        let mut results = vec![];
+        {
+            let locked_bot = shared_bot.lock().await;
+            for delivery in deliveries {
+                let keyboard = delivery.alert_id.map(telegram::alert_keyboard);
+                let result = telegram::try_send_message(&locked_bot, &delivery.chat_id, &delivery.text, keyboard).await;
+                results.push((delivery, result));
+            }
+        }
+
+        let mut locked_shared_state = shared_state.lock().await;
+        for (delivery, result) in results {
+            match result {
+                Ok(sent_message) => {
+                    if let Some(alert) = delivery.alert_id.and_then(|alert_id| locked_shared_state.alerts.get_mut(alert_id)) {
+                        alert.sent_messages.push((delivery.chat_id, sent_message.id));
+                    }
+                    locked_shared_state.outbox.remove(&delivery);
+                    if let Some(summary) = locked_shared_state.outbox.telegram_succeeded() {
+                        log::info!("{}", summary);
+                        locked_shared_state.outbox.push(&config.telegram.notification_chat_ids, &telegram::escape_html(&summary), None);
+                    }
+                },
+                Err(RequestError::RetryAfter(retry_after)) => {
+                    log::warn!("Telegram rate limit reached for chat {}, retrying in {}", delivery.chat_id, format_dhms(retry_after.as_secs()));
+                    locked_shared_state.outbox.retry_later(&delivery, retry_after);
+                },
+                Err(RequestError::Api(ApiError::MessageIsTooLong)) if locked_shared_state.outbox.too_long(&delivery) =>
+                    log::warn!("digest of {} notifications too long for chat {}, splitting it", delivery.count, delivery.chat_id),
+                Err(send_error) if telegram::is_permanent_error(&send_error) => {
+                    log::error!("Failed to send notification message to chat {}, dropping it: {}", delivery.chat_id, send_error);
+                    locked_shared_state.outbox.remove(&delivery);
+                },
+                Err(send_error) => {
+                    let retry_delay = locked_shared_state.outbox.failed(&delivery);
+                    log::error!("Failed to send notification message to chat {}, retrying in {}: {}", delivery.chat_id, format_dhms(retry_delay.as_secs()), send_error);
+                    if locked_shared_state.outbox.telegram_failed(config.telegram.fallback.as_ref()) {
+                        log::warn!("Telegram cannot be reached, sending the notifications to the fallback sinks too");
+                    }
+                }
+            }
+        }
+        save_if_changed(&config, &mut locked_shared_state.outbox);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str, alert_id: Option<AlertId>) -> OutboundMessage {
+        OutboundMessage { text: text.to_owned(), alert_id, queued_at: Timestamp::now(), forwarded: false }
+    }
+
+    fn rate_limit(global_per_second: u32, chat_per_minute: u32, digest_threshold: usize) -> RateLimit {
+        RateLimit { global_per_second, chat_per_minute, digest_threshold }
+    }
+
+    #[test]
+    fn digest_count_stops_at_alerts() {
+        assert_eq!(digest_count(&VecDeque::new(), 60), 0);
+        assert_eq!(digest_count(&VecDeque::from([message("a", None), message("b", None), message("c", None)]), 60), 3);
+        assert_eq!(digest_count(&VecDeque::from([message("a", None), message("b", None), message("alert", Some(1)), message("c", None)]), 60), 2);
+        assert_eq!(digest_count(&VecDeque::from([message("alert", Some(1)), message("a", None)]), 60), 0);
+    }
+
+    #[test]
+    fn digest_count_splits_long_digests() {
+        let long_text = "x".repeat(1900);
+        let messages = VecDeque::from([message(&long_text, None), message(&long_text, None), message(&long_text, None)]);
+        assert_eq!(digest_count(&messages, 60), 2);
+
+        let longer_text = "x".repeat(2020);
+        let messages = VecDeque::from([message(&longer_text, None), message(&longer_text, None)]);
+        assert_eq!(digest_count(&messages, 60), 1);
+
+        // a message too long for a digest is still sent on its own
+        let too_long_text = "x".repeat(MAX_MESSAGE_LENGTH);
+        assert_eq!(digest_count(&VecDeque::from([message(&too_long_text, None), message("a", None)]), 60), 1);
+    }
+
+    #[test]
+    fn digest_of_delayed_messages() {
+        let messages: VecDeque<OutboundMessage> = (0..200).map(|index| message(&format!("Door {index:>5}"), None)).collect();
+
+        // the time of the delayed messages is part of the budget, fewer of them fit in a digest
+        let count = digest_count(&messages, 0);
+        assert!(count < digest_count(&messages, 60));
+        assert!(digest_text(messages.iter().take(count), 0).len() <= MAX_MESSAGE_LENGTH);
+        assert!(digest_text(messages.iter().take(count + 1), 0).len() > MAX_MESSAGE_LENGTH - DIGEST_HEADER_LENGTH);
+
+        let mut outbox = Outbox::default();
+        for message in &messages {
+            outbox.push(&[ChatId(1)], &message.text, None);
+        }
+        let deliveries = outbox.next_deliveries(&rate_limit(30, 1, 3), 0, |_| true);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].count, count);
+        assert!(deliveries[0].text.len() <= MAX_MESSAGE_LENGTH);
+    }
+
+    #[test]
+    fn digest_too_long_is_split() {
+        let mut outbox = Outbox::default();
+        for text in ["a", "b", "c", "d", "e"] {
+            outbox.push(&[ChatId(1)], text, None);
+        }
+        let rate_limit = rate_limit(30, 3, 3);
+
+        let deliveries = outbox.next_deliveries(&rate_limit, 60, |_| true);
+        assert_eq!(deliveries[0].count, 5);
+        assert!(outbox.too_long(&deliveries[0]));
+        // nothing is dropped, the next digest is half as long
+        assert_eq!(outbox.len(), 5);
+        let deliveries = outbox.next_deliveries(&rate_limit, 60, |_| true);
+        assert_eq!(deliveries[0].count, 2);
+        outbox.remove(&deliveries[0]);
+        assert_eq!(outbox.len(), 3);
+
+        // a single message too long cannot be split
+        let mut outbox = Outbox::default();
+        outbox.push(&[ChatId(1)], "a", None);
+        let deliveries = outbox.next_deliveries(&rate_limit, 60, |_| true);
+        assert!(!outbox.too_long(&deliveries[0]));
+    }
+
+    #[test]
+    fn next_deliveries_single_message() {
+        let mut outbox = Outbox::default();
+        outbox.push(&[ChatId(1), ChatId(2)], "Door open", None);
+
+        let mut deliveries = outbox.next_deliveries(&rate_limit(30, 20, 5), 60, |_| true);
+        deliveries.sort_by_key(|delivery| delivery.chat_id.0);
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!((deliveries[0].chat_id, deliveries[0].text.as_str(), deliveries[0].count), (ChatId(1), "Door open", 1));
+        assert_eq!(deliveries[1].chat_id, ChatId(2));
+
+        // nothing is sent to a chat while its delivery is in flight
+        assert!(outbox.next_deliveries(&rate_limit(30, 20, 5), 60, |_| true).is_empty());
+        assert_eq!(outbox.len(), 2);
+
+        for delivery in &deliveries {
+            outbox.remove(delivery);
+        }
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn next_deliveries_digest() {
+        let mut outbox = Outbox::default();
+        for text in ["a", "b", "c", "d"] {
+            outbox.push(&[ChatId(1)], text, None);
+        }
+
+        let deliveries = outbox.next_deliveries(&rate_limit(30, 1, 3), 60, |_| true);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].count, 4);
+        assert!(deliveries[0].text.starts_with("4 notifications since "));
+        assert!(deliveries[0].text.ends_with("• a\n• b\n• c\n• d"));
+        assert_eq!(deliveries[0].alert_id, None);
+    }
+
+    #[test]
+    fn next_deliveries_below_digest_threshold() {
+        let mut outbox = Outbox::default();
+        outbox.push(&[ChatId(1)], "a", None);
+        outbox.push(&[ChatId(1)], "b", None);
+
+        let deliveries = outbox.next_deliveries(&rate_limit(30, 1, 3), 60, |_| true);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!((deliveries[0].text.as_str(), deliveries[0].count), ("a", 1));
+    }
+
+    #[test]
+    fn next_deliveries_digest_splitting() {
+        let long_text = "x".repeat(1900);
+        let mut outbox = Outbox::default();
+        for _ in 0..4 {
+            outbox.push(&[ChatId(1)], &long_text, None);
+        }
+        let rate_limit = rate_limit(30, 2, 2);
+
+        let deliveries = outbox.next_deliveries(&rate_limit, 60, |_| true);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].count, 2);
+        assert!(deliveries[0].text.starts_with("2 notifications since "));
+        outbox.remove(&deliveries[0]);
+        assert_eq!(outbox.len(), 2);
+
+        let deliveries = outbox.next_deliveries(&rate_limit, 60, |_| true);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].count, 2);
+        outbox.remove(&deliveries[0]);
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn next_deliveries_alerts() {
+        let mut outbox = Outbox::default();
+        outbox.push(&[ChatId(1)], "acknowledged alert", Some(1));
+        outbox.push(&[ChatId(1)], "pending alert", Some(2));
+        outbox.push(&[ChatId(1)], "a", None);
+        outbox.push(&[ChatId(1)], "b", None);
+
+        // the acknowledged alert is dropped and the pending one is sent on its own with its keyboard
+        let deliveries = outbox.next_deliveries(&rate_limit(30, 1, 2), 60, |alert_id| alert_id == 2);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!((deliveries[0].text.as_str(), deliveries[0].alert_id, deliveries[0].count), ("pending alert", Some(2), 1));
+        assert_eq!(outbox.len(), 3);
+    }
+
+    #[test]
+    fn alert_queued_until_delivered() {
+        let mut outbox = Outbox::default();
+        outbox.push(&[ChatId(1)], "alert", Some(1));
+        assert!(outbox.is_alert_queued(ChatId(1), 1));
+        assert!(!outbox.is_alert_queued(ChatId(1), 2));
+        assert!(!outbox.is_alert_queued(ChatId(2), 1));
+
+        let deliveries = outbox.next_deliveries(&rate_limit(30, 20, 5), 60, |_| true);
+        assert!(outbox.is_alert_queued(ChatId(1), 1));
+        outbox.remove(&deliveries[0]);
+        assert!(!outbox.is_alert_queued(ChatId(1), 1));
+    }
+
+    #[test]
+    fn next_deliveries_global_rate_limit() {
+        let mut outbox = Outbox::default();
+        outbox.push(&[ChatId(1), ChatId(2)], "Door open", None);
+
+        let deliveries = outbox.next_deliveries(&rate_limit(1, 20, 5), 60, |_| true);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(outbox.len(), 2);
+    }
+
+    #[test]
+    fn delayed_messages() {
+        // with a zero delayed notification age every message is delayed
+        assert!(message("Door open", None).text(0).starts_with("<i>Delayed notification from "));
+        assert!(message("Door open", None).digest_item(0).starts_with("• <i>"));
+        assert_eq!(message("Door open", None).text(60), "Door open");
+        assert_eq!(message("Door open", None).digest_item(60), "• Door open");
+    }
+
+}
@@ -0,0 +1,67 @@
+use regex::{Regex, RegexSet};
+use serde::Deserialize;
+
+use crate::ordered_map::{self, OrderedMap};
+
+/// Map keyed by regexes which are compiled once when the config is loaded.
+/// Entries are kept in the order of the config file, which gives the matching priority.
+#[derive(Debug)]
+pub struct RegexMap<V> {
+    entries: Vec<(Regex, V)>,
+    set: RegexSet
+}
+
+impl<V> RegexMap<V> {
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Regex, V)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// All the entries whose regex matches the text, in order
+    pub fn matches<'a>(&'a self, text: &str) -> impl Iterator<Item = &'a (Regex, V)> {
+        self.set.matches(text).into_iter().map(move |index| &self.entries[index])
+    }
+
+}
+
+impl<V> Default for RegexMap<V> {
+    fn default() -> Self {
+        Self {
+            entries: vec![],
+            set: RegexSet::empty()
+        }
+    }
+}
+
+impl<V: PartialEq> PartialEq for RegexMap<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries.len() == other.entries.len() &&
+            self.entries.iter().zip(other.entries.iter()).all(|((re, value), (other_re, other_value))| {
+                re.as_str() == other_re.as_str() && value == other_value
+            })
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for RegexMap<V> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let patterns: OrderedMap<String, V> = ordered_map::deserialize(deserializer)?;
+
+        let set = RegexSet::new(patterns.iter().map(|(pattern, _)| pattern)).map_err(serde::de::Error::custom)?;
+        let entries = patterns.into_iter().map(|(pattern, value)| {
+            Regex::new(&pattern).map(|re| (re, value)).map_err(serde::de::Error::custom)
+        }).collect::<Result<Vec<(Regex, V)>, D::Error>>()?;
+
+        Ok(Self { entries, set })
+    }
+}
@@ -0,0 +1,311 @@
+use std::str::FromStr;
+use serde::{Serialize,Deserialize};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Compare(Comparison, f64),
+    /// Inclusive range
+    Range(f64, f64),
+    /// Fires when the value differs from the value of the last time the rule fired by more than the given amount
+    ChangedBy(f64)
+}
+
+/// Numeric rule on a payload field value, written in the config as:
+/// `> 30`, `>= 30`, `< 5`, `<= 5`, `10..20` or `changed > 2`
+/// optionally followed by an hysteresis margin: `> 30 ±1`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rule {
+    pub condition: Condition,
+    pub hysteresis: f64
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RuleParseError {
+    #[error("invalid number: {0}")]
+    InvalidNumber(String),
+    #[error("invalid range: {0}")]
+    InvalidRange(String),
+    #[error("invalid rule: {0}")]
+    InvalidRule(String),
+    #[error("negative hysteresis: {0}")]
+    NegativeHysteresis(String)
+}
+
+fn parse_number(number_str: &str) -> Result<f64, RuleParseError> {
+    number_str.trim().parse().map_err(|_| RuleParseError::InvalidNumber(number_str.trim().to_owned()))
+}
+
+impl Rule {
+
+    /// Whether the state key looks like a rule rather than an exact value to match
+    pub fn is_rule(key: &str) -> bool {
+        let key = key.trim_start();
+        key.starts_with('>') || key.starts_with('<') || key.starts_with("changed") || key.contains("..")
+    }
+
+    /// Updates the rule state with the new value, returns whether the rule fired
+    pub fn evaluate(&self, value: f64, state: &mut RuleState) -> bool {
+        match self.condition {
+
+            Condition::ChangedBy(delta) => match state.reference {
+                Some(reference) if (value - reference).abs() > delta => {
+                    state.reference = Some(value);
+                    true
+                },
+                Some(_) => false,
+                None => {
+                    state.reference = Some(value);
+                    false
+                }
+            },
+
+            _ => {
+                if !state.active && self.is_met(value) {
+                    state.active = true;
+                    true
+                } else {
+                    if state.active && self.is_cleared(value) {
+                        state.active = false;
+                    }
+                    false
+                }
+            }
+
+        }
+    }
+
+    fn is_met(&self, value: f64) -> bool {
+        match self.condition {
+            Condition::Compare(Comparison::Greater, threshold) => value > threshold,
+            Condition::Compare(Comparison::GreaterOrEqual, threshold) => value >= threshold,
+            Condition::Compare(Comparison::Less, threshold) => value < threshold,
+            Condition::Compare(Comparison::LessOrEqual, threshold) => value <= threshold,
+            Condition::Range(low, high) => value >= low && value <= high,
+            Condition::ChangedBy(_) => false
+        }
+    }
+
+    // the value has to go past the threshold by the hysteresis margin for the rule to be able to fire again
+    fn is_cleared(&self, value: f64) -> bool {
+        let hysteresis = self.hysteresis;
+        match self.condition {
+            Condition::Compare(Comparison::Greater, threshold) => value <= threshold - hysteresis,
+            Condition::Compare(Comparison::GreaterOrEqual, threshold) => value < threshold - hysteresis,
+            Condition::Compare(Comparison::Less, threshold) => value >= threshold + hysteresis,
+            Condition::Compare(Comparison::LessOrEqual, threshold) => value > threshold + hysteresis,
+            Condition::Range(low, high) => value < low - hysteresis || value > high + hysteresis,
+            Condition::ChangedBy(_) => true
+        }
+    }
+
+}
+
+impl FromStr for Rule {
+    type Err = RuleParseError;
+
+    fn from_str(rule_str: &str) -> Result<Self, Self::Err> {
+        let (condition_str, hysteresis) = match rule_str.split_once('±') {
+            Some((condition_str, hysteresis_str)) => {
+                let hysteresis = parse_number(hysteresis_str)?;
+                if hysteresis < 0.0 {
+                    return Err(RuleParseError::NegativeHysteresis(rule_str.to_owned()));
+                }
+                (condition_str.trim(), hysteresis)
+            },
+            None => (rule_str.trim(), 0.0)
+        };
+
+        let condition = if let Some(delta_str) = condition_str.strip_prefix("changed") {
+            match delta_str.trim_start().strip_prefix('>') {
+                Some(delta_str) => Condition::ChangedBy(parse_number(delta_str)?),
+                None => return Err(RuleParseError::InvalidRule(rule_str.to_owned()))
+            }
+        } else if let Some(threshold_str) = condition_str.strip_prefix(">=") {
+            Condition::Compare(Comparison::GreaterOrEqual, parse_number(threshold_str)?)
+        } else if let Some(threshold_str) = condition_str.strip_prefix('>') {
+            Condition::Compare(Comparison::Greater, parse_number(threshold_str)?)
+        } else if let Some(threshold_str) = condition_str.strip_prefix("<=") {
+            Condition::Compare(Comparison::LessOrEqual, parse_number(threshold_str)?)
+        } else if let Some(threshold_str) = condition_str.strip_prefix('<') {
+            Condition::Compare(Comparison::Less, parse_number(threshold_str)?)
+        } else if let Some((low_str, high_str)) = condition_str.split_once("..") {
+            let (low, high) = (parse_number(low_str)?, parse_number(high_str)?);
+            if low > high {
+                return Err(RuleParseError::InvalidRange(rule_str.to_owned()));
+            }
+            Condition::Range(low, high)
+        } else {
+            return Err(RuleParseError::InvalidRule(rule_str.to_owned()));
+        };
+
+        Ok(Rule { condition, hysteresis })
+    }
+}
+
+#[derive(Serialize,Deserialize,Default,Debug,Clone)]
+pub struct RuleState {
+    /// Whether the condition has been met and not cleared since
+    active: bool,
+    /// Value of the last time a "changed by" rule fired
+    reference: Option<f64>
+}
+
+impl RuleState {
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+}
+
+/// Numeric value of a payload field, numbers as strings are accepted
+pub fn numeric_value(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_f64(),
+        serde_json::Value::String(string) => string.trim().parse().ok(),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule_str: &str) -> Rule {
+        rule_str.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_comparisons() {
+        assert_eq!(rule("> 30"), Rule { condition: Condition::Compare(Comparison::Greater, 30.0), hysteresis: 0.0 });
+        assert_eq!(rule(">=30"), Rule { condition: Condition::Compare(Comparison::GreaterOrEqual, 30.0), hysteresis: 0.0 });
+        assert_eq!(rule("< -5.5"), Rule { condition: Condition::Compare(Comparison::Less, -5.5), hysteresis: 0.0 });
+        assert_eq!(rule("<= 5 ±0.5"), Rule { condition: Condition::Compare(Comparison::LessOrEqual, 5.0), hysteresis: 0.5 });
+    }
+
+    #[test]
+    fn parse_range_and_changed() {
+        assert_eq!(rule("10..20 ±1"), Rule { condition: Condition::Range(10.0, 20.0), hysteresis: 1.0 });
+        assert_eq!(rule("changed > 2"), Rule { condition: Condition::ChangedBy(2.0), hysteresis: 0.0 });
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!("> abc".parse::<Rule>(), Err(RuleParseError::InvalidNumber("abc".to_owned())));
+        assert_eq!("20..10".parse::<Rule>(), Err(RuleParseError::InvalidRange("20..10".to_owned())));
+        assert_eq!("= 3".parse::<Rule>(), Err(RuleParseError::InvalidRule("= 3".to_owned())));
+        assert_eq!("changed 2".parse::<Rule>(), Err(RuleParseError::InvalidRule("changed 2".to_owned())));
+        assert_eq!("> 30 ±-1".parse::<Rule>(), Err(RuleParseError::NegativeHysteresis("> 30 ±-1".to_owned())));
+    }
+
+    #[test]
+    fn is_rule() {
+        assert!(Rule::is_rule(" > 30"));
+        assert!(Rule::is_rule("10..20"));
+        assert!(Rule::is_rule("changed > 2"));
+        assert!(!Rule::is_rule("true"));
+    }
+
+    #[test]
+    fn greater_fires_once_until_cleared() {
+        let rule = rule("> 30");
+        let mut state = RuleState::default();
+        assert!(!rule.evaluate(30.0, &mut state));
+        assert!(rule.evaluate(30.5, &mut state));
+        assert!(!rule.evaluate(35.0, &mut state));
+        assert!(state.is_active());
+        // exactly at the threshold the rule is cleared
+        assert!(!rule.evaluate(30.0, &mut state));
+        assert!(!state.is_active());
+        assert!(rule.evaluate(31.0, &mut state));
+    }
+
+    #[test]
+    fn greater_hysteresis_edges() {
+        let rule = rule("> 30 ±1");
+        let mut state = RuleState::default();
+        assert!(rule.evaluate(31.0, &mut state));
+        assert!(!rule.evaluate(29.5, &mut state));
+        assert!(state.is_active());
+        assert!(!rule.evaluate(30.5, &mut state));
+        assert!(!rule.evaluate(29.0, &mut state));
+        assert!(!state.is_active());
+        assert!(rule.evaluate(30.5, &mut state));
+    }
+
+    #[test]
+    fn greater_or_equal_hysteresis_edges() {
+        let rule = rule(">= 30 ±1");
+        let mut state = RuleState::default();
+        assert!(rule.evaluate(30.0, &mut state));
+        assert!(!rule.evaluate(29.0, &mut state));
+        assert!(state.is_active());
+        assert!(!rule.evaluate(28.9, &mut state));
+        assert!(!state.is_active());
+    }
+
+    #[test]
+    fn less_hysteresis_edges() {
+        let rule = rule("< 5 ±1");
+        let mut state = RuleState::default();
+        assert!(!rule.evaluate(5.0, &mut state));
+        assert!(rule.evaluate(4.0, &mut state));
+        assert!(!rule.evaluate(5.5, &mut state));
+        assert!(state.is_active());
+        assert!(!rule.evaluate(6.0, &mut state));
+        assert!(!state.is_active());
+    }
+
+    #[test]
+    fn less_or_equal_hysteresis_edges() {
+        let rule = rule("<= 5 ±1");
+        let mut state = RuleState::default();
+        assert!(rule.evaluate(5.0, &mut state));
+        assert!(!rule.evaluate(6.0, &mut state));
+        assert!(state.is_active());
+        assert!(!rule.evaluate(6.1, &mut state));
+        assert!(!state.is_active());
+    }
+
+    #[test]
+    fn range_hysteresis_edges() {
+        let rule = rule("10..20 ±1");
+        let mut state = RuleState::default();
+        assert!(rule.evaluate(10.0, &mut state));
+        assert!(!rule.evaluate(9.0, &mut state));
+        assert!(!rule.evaluate(21.0, &mut state));
+        assert!(state.is_active());
+        assert!(!rule.evaluate(21.5, &mut state));
+        assert!(!state.is_active());
+        assert!(rule.evaluate(20.0, &mut state));
+    }
+
+    #[test]
+    fn changed_by_reference() {
+        let rule = rule("changed > 2");
+        let mut state = RuleState::default();
+        // the first value is only the reference
+        assert!(!rule.evaluate(10.0, &mut state));
+        assert!(!rule.evaluate(12.0, &mut state));
+        assert!(rule.evaluate(12.5, &mut state));
+        // the reference is the value of the last firing
+        assert!(!rule.evaluate(10.5, &mut state));
+        assert!(rule.evaluate(10.0, &mut state));
+    }
+
+    #[test]
+    fn numeric_values() {
+        assert_eq!(numeric_value(&serde_json::json!(21.5)), Some(21.5));
+        assert_eq!(numeric_value(&serde_json::json!(" 42 ")), Some(42.0));
+        assert_eq!(numeric_value(&serde_json::json!("on")), None);
+        assert_eq!(numeric_value(&serde_json::json!(true)), None);
+    }
+
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+use chrono::{DateTime, Datelike, Local, TimeZone};
+
+use crate::config::{Config, ScheduledChange};
+use crate::{ProtectedSharedState, SharedConfig, telegram};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// the days are checked from the day before so that no change is missed around midnight
+fn occurrences_between(config: &Config, from: DateTime<Local>, to: DateTime<Local>) -> Vec<(DateTime<Local>, &ScheduledChange)> {
+    let mut occurrences = vec![];
+
+    let mut date = from.date_naive().pred_opt().unwrap_or(from.date_naive());
+    while date <= to.date_naive() {
+        for scheduled_change in config.schedule.iter().filter(|scheduled_change| scheduled_change.is_on(date.weekday())) {
+            // nonexistent local times (DST gap) are skipped
+            if let Some(occurrence) = Local.from_local_datetime(&date.and_time(scheduled_change.at)).earliest() {
+                if from < occurrence && occurrence <= to {
+                    occurrences.push((occurrence, scheduled_change));
+                }
+            }
+        }
+        date = match date.succ_opt() {
+            Some(next_date) => next_date,
+            None => break
+        };
+    }
+
+    occurrences.sort_by_key(|(occurrence, _)| *occurrence);
+    occurrences
+}
+
+/// The next scheduled change within a week
+pub fn next_change(config: &Config) -> Option<(DateTime<Local>, &ScheduledChange)> {
+    let now = Local::now();
+    occurrences_between(config, now, now + chrono::Duration::days(8)).into_iter().next()
+}
+
+async fn apply(config: &Config, shared_state: &ProtectedSharedState, occurrence: DateTime<Local>, scheduled_change: &ScheduledChange) {
+    let mut changes = vec![];
+
+    {
+        let mut locked_shared_state = shared_state.lock().await;
+
+        if let Some(mode) = &scheduled_change.mode {
+            locked_shared_state.arming_mode = mode.clone();
+            changes.push(format!("alarm mode set to <b>{}</b>", telegram::escape_html(mode)));
+        }
+
+        if let Some(notifications_enabled) = scheduled_change.notifications {
+            locked_shared_state.notifications_enabled = notifications_enabled;
+            changes.push(format!("notifications {}", if notifications_enabled { "enabled" } else { "disabled" }));
+        }
+    }
+
+    let message = format!("Scheduled change at {}: {}", occurrence.format("%H:%M"), changes.join(", "));
+    log::info!("{}", message);
+    telegram::notify_admins(config, shared_state, &message).await;
+}
+
+/// Applies the scheduled changes when their time comes, a manual change lasts until the next scheduled one
+pub async fn run(shared_config: SharedConfig, shared_state: ProtectedSharedState) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut last_check = Local::now();
+    loop {
+        interval.tick().await;
+        let now = Local::now();
+        let config = shared_config.read().await.clone();
+        for (occurrence, scheduled_change) in occurrences_between(&config, last_check, now) {
+            apply(&config, &shared_state, occurrence, scheduled_change).await;
+        }
+        last_check = now;
+    }
+}
@@ -1,14 +1,58 @@
 
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, io::Write, path::{Path, PathBuf}};
 use compound_duration::format_dhms;
 use serde::{Serialize,Deserialize};
 use derive_more::{Deref,DerefMut};
 use thiserror::Error;
 
+use crate::config::SensorRuleKey;
+use crate::rules::RuleState;
 use crate::time::{LastSeenDuration,Timestamp};
 
 pub type PayloadFieldName = String;
-pub type Data = HashMap<PayloadFieldName, serde_json::Value>;
+
+/// Field name of the value of payloads which are not JSON objects
+pub const PLAIN_VALUE_FIELD_NAME: &str = "value";
+
+/// Decoded MQTT payload
+///
+/// JSON objects are used as is, other JSON values (numbers, booleans, strings) and plain-text payloads
+/// such as `ON` or `23.5` become the `value` field of an object.
+pub struct Data(serde_json::Value);
+
+impl Data {
+
+    pub fn from_payload(payload: &str) -> Self {
+        let value = match serde_json::from_str(payload) {
+            Ok(value @ serde_json::Value::Object(_)) => return Self(value),
+            Ok(value) => value,
+            Err(_) => serde_json::Value::String(payload.trim().to_owned())
+        };
+        Self(serde_json::json!({ PLAIN_VALUE_FIELD_NAME: value }))
+    }
+
+    /// Gets a field by top-level key, JSON Pointer (`/ZbReceived/0x1234/Occupancy`) or dotted path (`state.contact`)
+    pub fn get(&self, field_name: &str) -> Option<&serde_json::Value> {
+        if field_name.starts_with('/') {
+            return self.0.pointer(field_name);
+        }
+
+        // a top-level key containing dots takes precedence over the dotted path
+        if let Some(value) = self.0.get(field_name) {
+            return Some(value);
+        }
+
+        if !field_name.contains('.') {
+            return None;
+        }
+
+        field_name.split('.').try_fold(&self.0, |value, segment| match value {
+            serde_json::Value::Array(array) => array.get(segment.parse::<usize>().ok()?),
+            _ => value.get(segment)
+        })
+    }
+
+}
 
 trait TimeSinceLastUpdate {
     fn time_since_last_update(&self) -> LastSeenDuration;
@@ -41,11 +85,27 @@ impl TimeSinceLastUpdate for CommonVoltageState {
 #[derive(Serialize,Deserialize,Default)]
 pub struct CommonState {
     battery: Option<CommonBatteryState>,
-    voltage: Option<CommonVoltageState>
+    voltage: Option<CommonVoltageState>,
+
+    /// Whether a low battery percent notification has been sent and the level has not recovered since
+    #[serde(default)]
+    pub battery_low: bool,
+
+    /// Whether a low battery voltage notification has been sent and the voltage has not recovered since
+    #[serde(default)]
+    pub voltage_low: bool
 }
 
 impl CommonState {
 
+    pub fn battery_value(&self) -> Option<u8> {
+        self.battery.as_ref().map(|battery_state| battery_state.value)
+    }
+
+    pub fn voltage_value(&self) -> Option<f32> {
+        self.voltage.as_ref().map(|voltage_state| voltage_state.value)
+    }
+
     pub fn update_battery(&mut self, battery: u8) {
         self.battery = Some(CommonBatteryState {
             update_timestamp: Timestamp::now(),
@@ -108,6 +168,68 @@ pub type SensorValue = serde_json::Value;
 
 pub type TriggerStates = HashMap<String, SensorValue>;
 
+/// The messages about a muted sensor are not sent
+#[derive(Serialize,Deserialize)]
+pub struct Mute {
+    /// Muted indefinitely when not set
+    pub until: Option<Timestamp>
+}
+
+impl Mute {
+
+    pub fn is_expired(&self) -> bool {
+        self.until.as_ref().is_some_and(Timestamp::is_past)
+    }
+
+}
+
+/// Value changes of a payload field within the flap detection window
+#[derive(Serialize,Deserialize,Default)]
+pub struct FlapState {
+    transitions: Vec<Timestamp>,
+
+    /// Whether a flapping notification has been sent and the value has not been stable since
+    pub flapping: bool
+}
+
+impl FlapState {
+
+    fn forget_transitions_before(&mut self, window: u64) {
+        self.transitions.retain(|transition| LastSeenDuration::new(transition).num_seconds() < window as i64);
+    }
+
+    /// returns: whether the field started flapping
+    pub fn record_transition(&mut self, max_transitions: usize, window: u64) -> bool {
+        self.forget_transitions_before(window);
+        self.transitions.push(Timestamp::now());
+        if !self.flapping && self.transitions.len() > max_transitions {
+            self.flapping = true;
+            return true;
+        }
+        false
+    }
+
+    pub fn transitions_count(&self) -> usize {
+        self.transitions.len()
+    }
+
+    /// returns: whether the field stopped flapping, once its value did not change for the whole window
+    pub fn check_stable(&mut self, window: u64) -> bool {
+        self.forget_transitions_before(window);
+        if self.flapping && self.transitions.is_empty() {
+            self.flapping = false;
+            return true;
+        }
+        false
+    }
+
+}
+
+pub type FlapStates = HashMap<PayloadFieldName, FlapState>;
+
+pub type FieldRuleStates = HashMap<SensorRuleKey, RuleState>;
+pub type RuleStates = HashMap<PayloadFieldName, FieldRuleStates>;
+
 #[derive(Serialize,Deserialize)]
 pub struct PrevData {
     #[serde(flatten)]
@@ -117,8 +239,21 @@ pub struct PrevData {
 
     pub name: SensorName,
 
-    #[serde(skip)]
-    pub trigger_states: TriggerStates
+    /// Whether an offline notification has been sent since the sensor was last seen
+    #[serde(default)]
+    pub offline: bool,
+
+    #[serde(default)]
+    pub trigger_states: TriggerStates,
+
+    #[serde(default)]
+    pub rule_states: RuleStates,
+
+    #[serde(default)]
+    pub mute: Option<Mute>,
+
+    #[serde(default)]
+    pub flap_states: FlapStates
 }
 
 impl PrevData {
@@ -128,7 +263,11 @@ impl PrevData {
             common: Default::default(),
             update_timestamp: Timestamp::now(),
             name: sensor_name,
-            trigger_states: Default::default()
+            offline: false,
+            trigger_states: Default::default(),
+            rule_states: Default::default(),
+            mute: None,
+            flap_states: Default::default()
         }
     }
 
@@ -136,6 +275,10 @@ impl PrevData {
         LastSeenDuration::new(&self.update_timestamp)
     }
 
+    pub fn is_muted(&self) -> bool {
+        self.mute.as_ref().is_some_and(|mute| !mute.is_expired())
+    }
+
     pub fn last_seen_now(&mut self) {
         self.update_timestamp = Timestamp::now();
     }
@@ -168,7 +311,7 @@ pub enum DataFileLoadError {
 pub enum DataFileSaveError {
     #[error("IO error")]
     IOError(std::io::Error),
-    #[error("deserialization error")]
+    #[error("serialization error")]
     SerializationError(serde_json::Error)
 }
 
@@ -181,25 +324,156 @@ impl PrevSensorsData {
         Self(HashMap::new())
     }
 
-    pub fn save_to_file<S: AsRef<Path>>(&self, file_path: S) -> Result<(), DataFileSaveError> {
-        match serde_json::to_string_pretty(self) {
+    /// Names of the tracked sensors matching the query: the exact name, otherwise the names containing it,
+    /// otherwise the names containing its characters in order, all case insensitive
+    pub fn find_sensor_names(&self, query: &str) -> Vec<SensorName> {
+        let query = query.to_lowercase();
+        let mut names: Vec<&SensorName> = self.values().map(|prev_data| &prev_data.name).collect();
+        names.sort();
+        names.dedup();
+
+        let exact_names: Vec<&SensorName> = names.iter().copied().filter(|name| name.to_lowercase() == query).collect();
+        if !exact_names.is_empty() {
+            return exact_names.into_iter().cloned().collect();
+        }
 
-            Ok(prev_sensors_data_json) =>
-                if let Err(error) = std::fs::write(file_path, prev_sensors_data_json) {
-                    Err(DataFileSaveError::IOError(error))
-                } else {
-                    Ok(())
-                },
+        let partial_names: Vec<&SensorName> = names.iter().copied().filter(|name| name.to_lowercase().contains(&query)).collect();
+        if !partial_names.is_empty() {
+            return partial_names.into_iter().cloned().collect();
+        }
 
-            Err(error) => Err(DataFileSaveError::SerializationError(error))
+        names.into_iter().filter(|name| {
+            let mut name_chars = name.to_lowercase().chars().collect::<Vec<char>>().into_iter();
+            query.chars().all(|query_char| name_chars.any(|name_char| name_char == query_char))
+        }).cloned().collect()
+    }
+
+    pub fn sensors_named<'a>(&'a mut self, name: &'a str) -> impl Iterator<Item = &'a mut PrevData> {
+        self.values_mut().filter(move |prev_data| prev_data.name == name)
+    }
+
+}
+
+/// Content of the sensors data file
+#[derive(Serialize,Deserialize)]
+pub struct DataFile<S> {
+    /// Not present in files written by older versions which only contained the sensors data
+    #[serde(default)]
+    pub notifications_enabled: Option<bool>,
+
+    #[serde(default)]
+    pub arming_mode: Option<String>,
+
+    pub sensors: S
+}
+
+pub fn path_with_suffix<P: AsRef<Path>>(file_path: P, suffix: &str) -> PathBuf {
+    let mut path = file_path.as_ref().as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
 
+/// Previous version of the data file, kept to be loaded when the data file is corrupted
+pub fn backup_file_path<P: AsRef<Path>>(file_path: P) -> PathBuf {
+    path_with_suffix(file_path, ".bak")
+}
+
+impl<S: Serialize> DataFile<S> {
+
+    // the data is first written to a temporary file which then replaces the data file
+    // so that a crash while writing cannot leave a truncated data file
+    pub fn save_to_file<P: AsRef<Path>>(&self, file_path: P) -> Result<(), DataFileSaveError> {
+        let data_file_json = serde_json::to_string_pretty(self).map_err(DataFileSaveError::SerializationError)?;
+
+        let temp_file_path = path_with_suffix(&file_path, ".tmp");
+        let mut temp_file = std::fs::File::create(&temp_file_path).map_err(DataFileSaveError::IOError)?;
+        temp_file.write_all(data_file_json.as_bytes()).map_err(DataFileSaveError::IOError)?;
+        temp_file.sync_all().map_err(DataFileSaveError::IOError)?;
+
+        if file_path.as_ref().exists() {
+            std::fs::rename(&file_path, backup_file_path(&file_path)).map_err(DataFileSaveError::IOError)?;
+        }
+
+        std::fs::rename(temp_file_path, file_path).map_err(DataFileSaveError::IOError)
+    }
+
+}
+
+impl DataFile<PrevSensorsData> {
+
+    /// Loads the data file, falling back to the backup file if it cannot be loaded
+    pub fn load_from_file<P: AsRef<Path>>(file_path: P) -> Result<Self, DataFileLoadError> {
+        match Self::load_from_single_file(&file_path) {
+            Ok(data_file) => Ok(data_file),
+            Err(load_error) => {
+                let backup_file_path = backup_file_path(&file_path);
+                if !backup_file_path.exists() {
+                    return Err(load_error);
+                }
+
+                log::warn!("failed to load sensors data file {:?}: {}, loading backup file {:?}", file_path.as_ref(), load_error, backup_file_path);
+                Self::load_from_single_file(&backup_file_path).map_err(|backup_load_error| {
+                    log::error!("failed to load sensors data backup file {:?}: {}", backup_file_path, backup_load_error);
+                    load_error
+                })
+            }
         }
     }
 
-    pub fn load_from_file<S: AsRef<Path>>(file_path: S) -> Result<Self, DataFileLoadError> {
+    fn load_from_single_file<P: AsRef<Path>>(file_path: P) -> Result<Self, DataFileLoadError> {
         let file = std::fs::File::open(file_path).map_err(DataFileLoadError::IOError)?;
         let reader = std::io::BufReader::new(file);
-        serde_json::from_reader(reader).map_err(DataFileLoadError::DeserializationError)
+        let data_file_json: serde_json::Value = serde_json::from_reader(reader).map_err(DataFileLoadError::DeserializationError)?;
+
+        // files written by older versions only contain the sensors data
+        if data_file_json.get("sensors").is_some() {
+            serde_json::from_value(data_file_json).map_err(DataFileLoadError::DeserializationError)
+        } else {
+            let sensors = serde_json::from_value(data_file_json).map_err(DataFileLoadError::DeserializationError)?;
+            Ok(Self { notifications_enabled: None, arming_mode: None, sensors })
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn json_pointer() {
+        let data = Data::from_payload(r#"{"ZbReceived": {"0x1234": {"Occupancy": 1}}, "a/b": 2}"#);
+        assert_eq!(data.get("/ZbReceived/0x1234/Occupancy"), Some(&json!(1)));
+        assert_eq!(data.get("/a~1b"), Some(&json!(2)));
+        assert_eq!(data.get("/ZbReceived/0x5678"), None);
+    }
+
+    #[test]
+    fn dotted_path() {
+        let data = Data::from_payload(r#"{"state": {"contact": false}, "readings": [{"temperature": 21.5}, 22]}"#);
+        assert_eq!(data.get("state.contact"), Some(&json!(false)));
+        assert_eq!(data.get("readings.0.temperature"), Some(&json!(21.5)));
+        assert_eq!(data.get("readings.1"), Some(&json!(22)));
+        assert_eq!(data.get("readings.2"), None);
+        assert_eq!(data.get("readings.first"), None);
+        assert_eq!(data.get("state.missing"), None);
+        assert_eq!(data.get("missing"), None);
+    }
+
+    #[test]
+    fn top_level_key_with_dots_takes_precedence() {
+        let data = Data::from_payload(r#"{"state.contact": "top", "state": {"contact": "nested"}}"#);
+        assert_eq!(data.get("state.contact"), Some(&json!("top")));
+    }
+
+    #[test]
+    fn plain_payloads() {
+        assert_eq!(Data::from_payload(" ON\n").get(PLAIN_VALUE_FIELD_NAME), Some(&json!("ON")));
+        assert_eq!(Data::from_payload("23.5").get(PLAIN_VALUE_FIELD_NAME), Some(&json!(23.5)));
+        assert_eq!(Data::from_payload("\"OFF\"").get(PLAIN_VALUE_FIELD_NAME), Some(&json!("OFF")));
+        assert_eq!(Data::from_payload("[1, 2]").get("value.1"), Some(&json!(2)));
+        assert_eq!(Data::from_payload(r#"{"contact": true}"#).get(PLAIN_VALUE_FIELD_NAME), None);
     }
 
 }
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use rumqttc::{AsyncClient, QoS};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::notifier::{Notification, Notifier, NotifierError};
+use crate::smtp::{self, Email};
+
+/// Timeout of the whole delivery of a notification, so that an unresponsive server does not pile up tasks
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Generic HTTP endpoint receiving the notifications as JSON
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+
+    /// Additional request headers, for instance for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>
+}
+
+/// ntfy topic, the URL includes the topic name
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Ntfy {
+    pub url: String,
+
+    /// Access token of protected topics
+    pub token: Option<String>,
+
+    /// From 1 (min) to 5 (max)
+    pub priority: Option<u8>
+}
+
+/// Gotify server, the token is the one of the application the messages are sent as
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Gotify {
+    pub url: String,
+
+    pub token: String,
+
+    /// From 0 (min) to 10 (max)
+    pub priority: Option<u8>
+}
+
+/// File the notifications are appended to, one line each
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct File {
+    pub path: String
+}
+
+/// Topic on the MQTT broker of the sensors, for instance driving a local siren
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Mqtt {
+    pub topic: String,
+
+    /// Published instead of the notification text, for instance {"state": "ON"}
+    pub payload: Option<String>,
+
+    #[serde(default)]
+    pub retain: bool
+}
+
+/// Destination of the notifications besides the Telegram chats, defined in the sinks section of the config and referenced by name
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sink {
+    Webhook(Webhook),
+    Email(Email),
+    Ntfy(Ntfy),
+    Gotify(Gotify),
+    Stdout,
+    File(File),
+    Mqtt(Mqtt)
+}
+
+fn url_errors(url: &str, context: &str) -> Vec<String> {
+    match reqwest::Url::parse(url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => vec![],
+        Ok(url) => vec![format!("{context}: unsupported URL scheme {:?}", url.scheme())],
+        Err(parse_error) => vec![format!("{context}: invalid URL {url:?}: {parse_error}")]
+    }
+}
+
+fn priority_errors(priority: Option<u8>, range: std::ops::RangeInclusive<u8>, context: &str) -> Vec<String> {
+    match priority {
+        Some(priority) if !range.contains(&priority) => vec![format!("{context}: priority {priority} must be between {} and {}", range.start(), range.end())],
+        _ => vec![]
+    }
+}
+
+impl Sink {
+
+    pub fn errors(&self, context: &str) -> Vec<String> {
+        match self {
+            Sink::Webhook(webhook) => url_errors(&webhook.url, context),
+            Sink::Email(email) => email.errors(context),
+            Sink::Ntfy(ntfy) => [url_errors(&ntfy.url, context), priority_errors(ntfy.priority, 1..=5, context)].concat(),
+            Sink::Gotify(gotify) => [url_errors(&gotify.url, context), priority_errors(gotify.priority, 0..=10, context)].concat(),
+            Sink::Mqtt(mqtt) if mqtt.topic.is_empty() || mqtt.topic.contains(['+', '#']) =>
+                vec![format!("{context}: invalid topic {:?}, it cannot be empty or contain wildcards", mqtt.topic)],
+            Sink::Stdout | Sink::File(_) | Sink::Mqtt(_) => vec![]
+        }
+    }
+
+}
+
+fn http_client() -> Result<reqwest::Client, NotifierError> {
+    reqwest::Client::builder().timeout(SEND_TIMEOUT).build().map_err(NotifierError::HttpError)
+}
+
+impl Notifier for Webhook {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let body = serde_json::json!({
+            "message": notification.text(),
+            "html": notification.message,
+            "title": notification.title(),
+            "timestamp": notification.timestamp.to_rfc3339()
+        });
+
+        let mut request = http_client()?.post(&self.url).json(&body);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        request.send().await.and_then(reqwest::Response::error_for_status).map_err(NotifierError::HttpError)?;
+        Ok(())
+    }
+}
+
+impl Notifier for Email {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        tokio::time::timeout(SEND_TIMEOUT, smtp::send_mail(self, &notification.title(), &notification.text())).await
+            .map_err(|_| NotifierError::Timeout)?
+            .map_err(NotifierError::SmtpError)
+    }
+}
+
+// the title is sent as a query parameter since the headers cannot contain UTF-8
+impl Notifier for Ntfy {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let mut query = vec![("title", notification.title())];
+        if let Some(priority) = self.priority {
+            query.push(("priority", priority.to_string()));
+        }
+
+        let mut request = http_client()?.post(&self.url).query(&query).body(notification.text());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request.send().await.and_then(reqwest::Response::error_for_status).map_err(NotifierError::HttpError)?;
+        Ok(())
+    }
+}
+
+impl Notifier for Gotify {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let mut body = serde_json::json!({
+            "title": notification.title(),
+            "message": notification.text()
+        });
+        if let Some(priority) = self.priority {
+            body["priority"] = priority.into();
+        }
+
+        http_client()?.post(format!("{}/message", self.url.trim_end_matches('/')))
+            .header("X-Gotify-Key", &self.token)
+            .json(&body)
+            .send().await.and_then(reqwest::Response::error_for_status).map_err(NotifierError::HttpError)?;
+        Ok(())
+    }
+}
+
+impl Notifier for File {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await.map_err(NotifierError::IOError)?;
+        file.write_all(notification.line().as_bytes()).await.map_err(NotifierError::IOError)
+    }
+}
+
+// the publication is queued, it is sent once the event loop polls it
+async fn publish(client: &AsyncClient, mqtt: &Mqtt, notification: &Notification) -> Result<(), NotifierError> {
+    let payload = mqtt.payload.clone().unwrap_or_else(|| notification.text());
+    client.publish(&mqtt.topic, QoS::AtLeastOnce, mqtt.retain, payload).await.map_err(NotifierError::MqttError)
+}
+
+/// A sink with the MQTT client the mqtt sinks publish with, the client changes when the broker settings are reloaded
+pub struct SinkNotifier {
+    pub sink: Sink,
+    pub mqtt_client: Option<AsyncClient>
+}
+
+impl Notifier for SinkNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        match &self.sink {
+            Sink::Webhook(webhook) => webhook.notify(notification).await,
+            Sink::Email(email) => email.notify(notification).await,
+            Sink::Ntfy(ntfy) => ntfy.notify(notification).await,
+            Sink::Gotify(gotify) => gotify.notify(notification).await,
+            Sink::Stdout => {
+                print!("{}", notification.line());
+                Ok(())
+            },
+            Sink::File(file) => file.notify(notification).await,
+            Sink::Mqtt(mqtt) => match &self.mqtt_client {
+                Some(client) => publish(client, mqtt, notification).await,
+                None => Err(NotifierError::MqttNotConnected)
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::sync::Arc;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::tls::{self, TlsConfigError};
+
+/// Host name sent in the EHLO command
+const CLIENT_NAME: &str = "localhost";
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with the STARTTLS command
+    #[default]
+    Starttls,
+    /// TLS from the start of the connection
+    Tls,
+    /// Unencrypted connection, only meant for local relays
+    None
+}
+
+/// SMTP server and addresses of the email notifications
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Email {
+    pub hostname: String,
+
+    /// 587 with STARTTLS, 465 with TLS and 25 without encryption by default
+    pub port: Option<u16>,
+
+    #[serde(default)]
+    pub security: SmtpSecurity,
+
+    pub username: Option<String>,
+
+    pub password: Option<String>,
+
+    pub from: String,
+
+    pub to: Vec<String>,
+
+    /// CA bundle trusted besides the system certificates
+    pub ca_file: Option<String>,
+
+    /// Do not verify the server certificate, only meant for lab setups
+    #[serde(default)]
+    pub insecure_skip_verify: bool
+}
+
+impl Email {
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.security {
+            SmtpSecurity::Starttls => 587,
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::None => 25
+        })
+    }
+
+    pub fn errors(&self, context: &str) -> Vec<String> {
+        let mut errors = vec![];
+
+        if self.hostname.is_empty() {
+            errors.push(format!("{context}: hostname cannot be empty"));
+        }
+
+        if self.from.is_empty() {
+            errors.push(format!("{context}: from address cannot be empty"));
+        }
+
+        if self.to.is_empty() {
+            errors.push(format!("{context}: at least one recipient address is required in to"));
+        }
+
+        if self.username.is_some() != self.password.is_some() {
+            errors.push(format!("{context}: username and password must be specified together"));
+        }
+
+        errors
+    }
+
+}
+
+#[derive(Debug, Error)]
+pub enum SmtpError {
+    #[error("IO error: {0}")]
+    IOError(std::io::Error),
+    #[error("TLS error: {0}")]
+    TlsError(TlsConfigError),
+    #[error("invalid server name {0:?}")]
+    InvalidServerName(String),
+    #[error("unexpected reply to {0}: {1}")]
+    UnexpectedReply(String, String),
+    #[error("connection closed by the server")]
+    ConnectionClosed
+}
+
+// a reply spans several lines when there is a dash after the code
+async fn read_reply<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut BufReader<S>) -> Result<(u16, String), SmtpError> {
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.map_err(SmtpError::IOError)? == 0 {
+            return Err(SmtpError::ConnectionClosed);
+        }
+        reply.push_str(&line);
+        if line.as_bytes().get(3) != Some(&b'-') {
+            let code = line.get(..3).and_then(|code| code.parse().ok()).unwrap_or_default();
+            return Ok((code, reply.trim_end().to_owned()));
+        }
+    }
+}
+
+// the reply code has to be in the same class as the expected one, 2xx for most commands
+async fn expect_reply<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut BufReader<S>, description: &str, expected_code: u16) -> Result<(), SmtpError> {
+    let (code, reply) = read_reply(stream).await?;
+    if code / 100 != expected_code / 100 {
+        return Err(SmtpError::UnexpectedReply(description.to_owned(), reply));
+    }
+    Ok(())
+}
+
+// the description is used in the errors instead of the command, which can contain credentials
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut BufReader<S>, command: &str, description: &str, expected_code: u16) -> Result<(), SmtpError> {
+    stream.write_all(format!("{command}\r\n").as_bytes()).await.map_err(SmtpError::IOError)?;
+    stream.flush().await.map_err(SmtpError::IOError)?;
+    expect_reply(stream, description, expected_code).await
+}
+
+async fn tls_connect<S: AsyncRead + AsyncWrite + Unpin>(email: &Email, stream: S) -> Result<tokio_rustls::client::TlsStream<S>, SmtpError> {
+    let client_config = tls::load_system_client_config(email.ca_file.as_deref(), email.insecure_skip_verify).map_err(SmtpError::TlsError)?;
+    let server_name = rustls::ServerName::try_from(email.hostname.as_str()).map_err(|_| SmtpError::InvalidServerName(email.hostname.clone()))?;
+    TlsConnector::from(Arc::new(client_config)).connect(server_name, stream).await.map_err(SmtpError::IOError)
+}
+
+// non ASCII subjects are encoded as specified by RFC 2047
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_owned();
+    }
+    format!("=?UTF-8?B?{}?=", base64::encode(value))
+}
+
+// the body is base64 encoded so that it does not depend on the 8BITMIME extension and never needs dot stuffing
+fn mail_data(email: &Email, subject: &str, body: &str) -> String {
+    let body_base64 = base64::encode(body.replace('\n', "\r\n"));
+    let body_lines = body_base64.as_bytes().chunks(76).map(|chunk| String::from_utf8_lossy(chunk).into_owned()).collect::<Vec<String>>().join("\r\n");
+
+    format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n.",
+        email.from,
+        email.to.join(", "),
+        encode_header(subject),
+        chrono::Local::now().to_rfc2822(),
+        body_lines
+    )
+}
+
+async fn session<S: AsyncRead + AsyncWrite + Unpin>(mut stream: BufReader<S>, email: &Email, subject: &str, body: &str) -> Result<(), SmtpError> {
+    command(&mut stream, &format!("EHLO {CLIENT_NAME}"), "EHLO", 250).await?;
+
+    if let (Some(username), Some(password)) = (&email.username, &email.password) {
+        let credentials = base64::encode(format!("\0{username}\0{password}"));
+        command(&mut stream, &format!("AUTH PLAIN {credentials}"), "AUTH", 235).await?;
+    }
+
+    command(&mut stream, &format!("MAIL FROM:<{}>", email.from), "MAIL FROM", 250).await?;
+    for to in &email.to {
+        command(&mut stream, &format!("RCPT TO:<{to}>"), &format!("RCPT TO {to}"), 250).await?;
+    }
+    command(&mut stream, "DATA", "DATA", 354).await?;
+    command(&mut stream, &mail_data(email, subject, body), "message", 250).await?;
+
+    // the mail has been accepted, a failure to quit cleanly does not matter
+    if let Err(quit_error) = command(&mut stream, "QUIT", "QUIT", 221).await {
+        log::debug!("SMTP QUIT failed: {}", quit_error);
+    }
+
+    Ok(())
+}
+
+/// Sends a plain text email to the recipients of the email settings
+pub async fn send_mail(email: &Email, subject: &str, body: &str) -> Result<(), SmtpError> {
+    let tcp_stream = TcpStream::connect((email.hostname.as_str(), email.port())).await.map_err(SmtpError::IOError)?;
+
+    match email.security {
+        SmtpSecurity::Tls => {
+            let mut stream = BufReader::new(tls_connect(email, tcp_stream).await?);
+            expect_reply(&mut stream, "connection", 220).await?;
+            session(stream, email, subject, body).await
+        },
+        SmtpSecurity::Starttls => {
+            let mut stream = BufReader::new(tcp_stream);
+            expect_reply(&mut stream, "connection", 220).await?;
+            command(&mut stream, &format!("EHLO {CLIENT_NAME}"), "EHLO", 250).await?;
+            command(&mut stream, "STARTTLS", "STARTTLS", 220).await?;
+            let stream = BufReader::new(tls_connect(email, stream.into_inner()).await?);
+            session(stream, email, subject, body).await
+        },
+        SmtpSecurity::None => {
+            let mut stream = BufReader::new(tcp_stream);
+            expect_reply(&mut stream, "connection", 220).await?;
+            session(stream, email, subject, body).await
+        }
+    }
+}
@@ -1,37 +1,52 @@
 
-use teloxide::{prelude::*, dispatching};
+use teloxide::{prelude::*, dispatching, ApiError, RequestError};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use Sync;
 
-use crate::ProtectedSharedState;
-use crate::config;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::{ProtectedSharedState, ReloadRequestSender, SharedConfig};
+use crate::alerts::{self, AlertId};
+use crate::schedule;
+use crate::sensors::{Mute, PrevSensorsData, SensorName};
+use crate::time::{self, Timestamp};
+use compound_duration::format_dhms;
+use crate::config::{self, Config};
 
 pub type SharedBot = Arc<Mutex<AutoSend<Bot>>>;
 
-pub async fn start_repl(config: &config::Telegram, shared_state: ProtectedSharedState) -> SharedBot {
+pub async fn start_repl(shared_config: SharedConfig, shared_state: ProtectedSharedState, reload_sender: ReloadRequestSender) -> SharedBot {
 
-    let bot = Bot::new(&config.token).auto_send();
+    let bot = Bot::new(&shared_config.read().await.telegram.token).auto_send();
     let shared_bot = Arc::new(Mutex::new(bot.clone()));
     let repl_shared_bot = shared_bot.clone();
 
     tokio::spawn(
-        repl_with_deps(bot, repl_shared_bot, shared_state, config.valid_chat_ids(), |message: Message, _bot: AutoSend<Bot>, shared_bot: SharedBot, shared_state: ProtectedSharedState, valid_chat_ids: Vec<ChatId>| async move {
-            if valid_chat_ids.contains(&message.chat.id) {
+        repl_with_deps(bot, repl_shared_bot, shared_state, shared_config, reload_sender, |message: Message, _bot: AutoSend<Bot>, shared_bot: SharedBot, shared_state: ProtectedSharedState, shared_config: SharedConfig, reload_sender: ReloadRequestSender| async move {
+            let config = shared_config.read().await.clone();
+            if config.telegram.valid_chat_ids().contains(&message.chat.id) {
                 if let Some(command) = message.text() {
                     log::debug!("Got message with text: {:?}", command);
                     let locked_bot = shared_bot.lock().await;
-                    handle_commands(&locked_bot, &message.chat.id, command, &shared_state).await;
+                    handle_commands(&locked_bot, &message.chat.id, command, &shared_state, &config, &reload_sender).await;
                 }
             }
             respond(())
+        }, |callback_query: CallbackQuery, shared_bot: SharedBot, shared_state: ProtectedSharedState, shared_config: SharedConfig| async move {
+            let config = shared_config.read().await.clone();
+            let chat_id = callback_query.message.as_ref().map(|message| message.chat.id);
+            if chat_id.is_some_and(|chat_id| config.telegram.notified_chat_ids().contains(&chat_id)) {
+                handle_callback_query(&shared_bot, &callback_query, &shared_state).await;
+            }
+            respond(())
         })
     );
 
     shared_bot
 }
 
-async fn repl<'a, R, H, E, Args>(bot: R, handler: H)
+async fn repl<R, H, E, Args>(bot: R, handler: H)
 where
     H: dptree::di::Injectable<DependencyMap, Result<(), E>, Args> + Send + Sync + 'static,
     Result<(), E>: OnError<E>,
@@ -55,25 +70,32 @@ where
         .await;
 }
 
-async fn repl_with_deps<'a, R, H, E, D1, D2, D3, Args>(bot: R, dep1: D1, dep2: D2, dep3: D3, handler: H)
+#[allow(clippy::too_many_arguments)]
+async fn repl_with_deps<R, H, CH, E, D1, D2, D3, D4, Args, CArgs>(bot: R, dep1: D1, dep2: D2, dep3: D3, dep4: D4, handler: H, callback_query_handler: CH)
 where
     H: dptree::di::Injectable<DependencyMap, Result<(), E>, Args> + Send + Sync + 'static,
+    CH: dptree::di::Injectable<DependencyMap, Result<(), E>, CArgs> + Send + Sync + 'static,
     Result<(), E>: OnError<E>,
     E: std::fmt::Debug + Send + Sync + 'static,
     R: Requester + Send + Sync + Clone + 'static,
     <R as Requester>::GetUpdates: Send,
     D1: Send + Sync + 'static,
     D2: Send + Sync + 'static,
-    D3: Send + Sync + 'static
+    D3: Send + Sync + 'static,
+    D4: Send + Sync + 'static
 {
     let listener = dispatching::update_listeners::polling_default(bot.clone()).await;
 
-    // Other update types are of no interest to use since this REPL is only for
-    // messages. See <https://github.com/teloxide/teloxide/issues/557>.
+    // Other update types than messages and callback queries from the inline keyboards are of no interest.
+    // See <https://github.com/teloxide/teloxide/issues/557>.
     let ignore_update = |_upd| Box::pin(async {});
 
-    Dispatcher::builder(bot, Update::filter_message().chain(dptree::endpoint(handler)))
-        .dependencies(dptree::deps![dep1, dep2, dep3])
+    let update_handler = dptree::entry()
+        .branch(Update::filter_message().chain(dptree::endpoint(handler)))
+        .branch(Update::filter_callback_query().chain(dptree::endpoint(callback_query_handler)));
+
+    Dispatcher::builder(bot, update_handler)
+        .dependencies(dptree::deps![dep1, dep2, dep3, dep4])
         .default_handler(ignore_update)
         .build()
         .dispatch_with_listener(
@@ -83,31 +105,175 @@ where
         .await;
 }
 
-pub async fn send_message(bot: &AutoSend<Bot>, chat_id: &ChatId, message: &str) {
-    let send_message = bot
-        .send_message(*chat_id, message)
-        .parse_mode(teloxide::types::ParseMode::Html);
-    if let Err(send_error) = send_message.await {
-        log::error!("Failed to send notification message: {}", send_error);
+fn is_parse_error(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(ApiError::CantParseEntities) => true,
+        RequestError::Api(ApiError::Unknown(description)) => description.contains("can't parse entities"),
+        _ => false
     }
 }
 
-pub async fn shared_bot_send_message(shared_bot: &tokio::sync::MutexGuard<'_, AutoSend<Bot>>, chat_id: &ChatId, message: &str) {
-    let send_message = shared_bot
+/// Whether sending the message again cannot succeed, for instance when the bot has been removed from the chat.
+/// An invalid token is not specific to the chat, the message is sent again once it has been fixed
+pub fn is_permanent_error(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(ApiError::Unknown(_) | ApiError::NotFound) => false,
+        RequestError::Api(_) | RequestError::MigrateToChatId(_) => true,
+        _ => false
+    }
+}
+
+/// Removes the tags and decodes the entities, for the messages Telegram fails to parse and the sinks without HTML support
+pub fn html_to_plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&")
+}
+
+/// Sends an HTML message with an optional inline keyboard, resent as plain text if Telegram fails to parse it so that the notification is not lost
+pub async fn try_send_message(bot: &AutoSend<Bot>, chat_id: &ChatId, message: &str, keyboard: Option<InlineKeyboardMarkup>) -> Result<Message, RequestError> {
+    let mut send_message = bot
         .send_message(*chat_id, message)
         .parse_mode(teloxide::types::ParseMode::Html);
-    if let Err(send_error) = send_message.await {
+    if let Some(keyboard) = &keyboard {
+        send_message = send_message.reply_markup(keyboard.clone());
+    }
+    match send_message.await {
+        Err(send_error) if is_parse_error(&send_error) => {
+            log::warn!("Failed to parse notification message {:?}: {}, sending it as plain text", message, send_error);
+            let mut send_message = bot.send_message(*chat_id, html_to_plain_text(message));
+            if let Some(keyboard) = keyboard {
+                send_message = send_message.reply_markup(keyboard);
+            }
+            send_message.await
+        },
+        result => result
+    }
+}
+
+pub async fn send_message(bot: &AutoSend<Bot>, chat_id: &ChatId, message: &str) {
+    if let Err(send_error) = try_send_message(bot, chat_id, message, None).await {
         log::error!("Failed to send notification message: {}", send_error);
     }
 }
 
-async fn handle_commands(bot: &AutoSend<Bot>, chat_id: &ChatId, command: &str, shared_data: &ProtectedSharedState) {
+const ACKNOWLEDGE_CALLBACK_PREFIX: &str = "ack:";
+const SNOOZE_CALLBACK_PREFIX: &str = "snooze:";
+
+/// Acknowledge and snooze buttons of an alert message
+pub fn alert_keyboard(alert_id: AlertId) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("Acknowledge".to_owned(), format!("{ACKNOWLEDGE_CALLBACK_PREFIX}{alert_id}")),
+        InlineKeyboardButton::callback(format!("Snooze {} min", alerts::SNOOZE_DURATION.as_secs() / 60), format!("{SNOOZE_CALLBACK_PREFIX}{alert_id}"))
+    ]])
+}
+
+async fn answer_callback_query(bot: &AutoSend<Bot>, callback_query: &CallbackQuery, text: &str) {
+    if let Err(answer_error) = bot.answer_callback_query(&callback_query.id).text(text).await {
+        log::error!("Failed to answer callback query: {}", answer_error);
+    }
+}
+
+// the other chats of the alert are told who acknowledged or snoozed it
+async fn handle_callback_query(shared_bot: &SharedBot, callback_query: &CallbackQuery, shared_state: &ProtectedSharedState) {
+    let data = callback_query.data.as_deref().unwrap_or_default();
+    let user_name = escape_html(&callback_query.from.full_name());
+    let query_chat_id = callback_query.message.as_ref().map(|message| message.chat.id);
+
+    if let Some(alert_id) = data.strip_prefix(ACKNOWLEDGE_CALLBACK_PREFIX).and_then(|alert_id| alert_id.parse().ok()) {
+        let alert = {
+            let mut locked_shared_state = shared_state.lock().await;
+            let alert = locked_shared_state.alerts.acknowledge(alert_id);
+            if let Some(alert) = &alert {
+                let message = format!("Alert acknowledged by <b>{}</b>:\n{}", user_name, alert.message);
+                let other_chat_ids: Vec<ChatId> = alert.chat_ids.iter().copied().filter(|chat_id| Some(*chat_id) != query_chat_id).collect();
+                locked_shared_state.outbox.push(&other_chat_ids, &message, None);
+            }
+            alert
+        };
+        let locked_bot = shared_bot.lock().await;
+        match alert {
+            Some(alert) => {
+                log::info!("alert {} acknowledged by {}", alert_id, callback_query.from.full_name());
+                answer_callback_query(&locked_bot, callback_query, "Alert acknowledged").await;
+                for (chat_id, message_id) in &alert.sent_messages {
+                    if let Err(edit_error) = locked_bot.edit_message_reply_markup(*chat_id, *message_id).await {
+                        log::debug!("Failed to remove the alert keyboard: {}", edit_error);
+                    }
+                }
+            },
+            None => answer_callback_query(&locked_bot, callback_query, "This alert is no longer pending").await
+        }
+    } else if let Some(alert_id) = data.strip_prefix(SNOOZE_CALLBACK_PREFIX).and_then(|alert_id| alert_id.parse().ok()) {
+        let snooze_minutes = alerts::SNOOZE_DURATION.as_secs() / 60;
+        let snoozed = {
+            let mut locked_shared_state = shared_state.lock().await;
+            let alert = locked_shared_state.alerts.snooze(alert_id).map(|alert| (alert.message.clone(), alert.chat_ids.clone()));
+            if let Some((alert_message, chat_ids)) = &alert {
+                let message = format!("Alert snoozed for {} min by <b>{}</b>:\n{}", snooze_minutes, user_name, alert_message);
+                let other_chat_ids: Vec<ChatId> = chat_ids.iter().copied().filter(|chat_id| Some(*chat_id) != query_chat_id).collect();
+                locked_shared_state.outbox.push(&other_chat_ids, &message, None);
+            }
+            alert.is_some()
+        };
+        let locked_bot = shared_bot.lock().await;
+        match snoozed {
+            true => {
+                log::info!("alert {} snoozed by {}", alert_id, callback_query.from.full_name());
+                answer_callback_query(&locked_bot, callback_query, &format!("Alert snoozed for {snooze_minutes} min")).await;
+            },
+            false => answer_callback_query(&locked_bot, callback_query, "This alert is no longer pending").await
+        }
+    } else {
+        log::warn!("unknown callback query data: {:?}", data);
+    }
+}
+
+/// Queues a message for the admin chats, it is sent again until Telegram confirms its delivery like the notifications
+pub async fn notify_admins(config: &Config, shared_state: &ProtectedSharedState, message: &str) {
+    match &config.telegram.admin_chat_ids {
+        Some(admin_chat_ids) => shared_state.lock().await.outbox.push(admin_chat_ids, message, None),
+        None => log::warn!("no admin chat IDs defined, not sending: {}", message)
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// tells the chat when the query does not match exactly one sensor
+async fn find_sensor_name(bot: &AutoSend<Bot>, chat_id: &ChatId, prev_sensors_data: &PrevSensorsData, query: &str) -> Option<SensorName> {
+    let mut sensor_names = prev_sensors_data.find_sensor_names(query);
+    match sensor_names.len() {
+        0 => {
+            send_message(bot, chat_id, format!("No sensor matching <b>{}</b>", escape_html(query)).as_str()).await;
+            None
+        },
+        1 => sensor_names.pop(),
+        _ => {
+            let names = sensor_names.iter().map(|name| format!("• {}", escape_html(name))).collect::<Vec<String>>().join("\n");
+            send_message(bot, chat_id, format!("Several sensors match <b>{}</b>:\n{}", escape_html(query), names).as_str()).await;
+            None
+        }
+    }
+}
+
+async fn handle_commands(bot: &AutoSend<Bot>, chat_id: &ChatId, command: &str, shared_data: &ProtectedSharedState, config: &Config, reload_sender: &ReloadRequestSender) {
     let mut locked_shared_data = shared_data.lock().await;
+    let (command, argument) = command.split_once(' ').map_or((command, ""), |(command, argument)| (command, argument.trim()));
     match command {
 
         "/battery" => {
-            let battery_info = locked_shared_data.prev_sensors_data.iter().map(|(_mqtt_topic, prev_sensor_data)| {
-                format!("• <b>{}</b>: {} / {} ({})", prev_sensor_data.name, prev_sensor_data.common.battery_value_str(), prev_sensor_data.common.voltage_value_str(), prev_sensor_data.common.time_max_since_last_update_str())
+            let battery_info = locked_shared_data.prev_sensors_data.values().map(|prev_sensor_data| {
+                format!("• <b>{}</b>: {} / {} ({})", escape_html(&prev_sensor_data.name), prev_sensor_data.common.battery_value_str(), prev_sensor_data.common.voltage_value_str(), prev_sensor_data.common.time_max_since_last_update_str())
             }).collect::<Vec<String>>().join("\n");
             let message = if battery_info.is_empty() { "No data" } else { battery_info.as_str() };
             send_message(bot, chat_id, message).await
@@ -123,9 +289,113 @@ async fn handle_commands(bot: &AutoSend<Bot>, chat_id: &ChatId, command: &str, s
             send_message(bot, chat_id, "Notifications disabled").await;
         },
 
+        "/arm" => {
+            if argument.is_empty() {
+                let modes = config.arming_modes.iter().map(|mode| escape_html(mode)).collect::<Vec<String>>().join(", ");
+                send_message(bot, chat_id, format!("Usage: /arm &lt;mode&gt;, available modes: {}", if modes.is_empty() { "none" } else { modes.as_str() }).as_str()).await;
+            } else if config.is_arming_mode(argument) {
+                log::info!("arming mode set to {} from chat {}", argument, chat_id);
+                locked_shared_data.arming_mode = argument.to_owned();
+                send_message(bot, chat_id, format!("Alarm mode set to <b>{}</b>", escape_html(argument)).as_str()).await;
+            } else {
+                send_message(bot, chat_id, format!("Unknown mode <b>{}</b>", escape_html(argument)).as_str()).await;
+            }
+        },
+
+        "/disarm" if !config.uses_arming_modes() => {
+            send_message(bot, chat_id, "No arming modes are defined, use /disable to stop the notifications").await;
+        },
+
+        "/disarm" => {
+            log::info!("disarmed from chat {}", chat_id);
+            locked_shared_data.arming_mode = config::DISARMED_MODE.to_owned();
+            send_message(bot, chat_id, "Alarm disarmed, only the messages listing the disarmed mode are sent").await;
+        },
+
+        "/mute" => {
+            // the duration is optional and sensor names can contain spaces
+            let (query, duration) = match argument.rsplit_once(' ') {
+                Some((query, duration_str)) => match time::parse_duration(duration_str) {
+                    Some(duration) => (query.trim(), Some(duration)),
+                    None => (argument, None)
+                },
+                None => (argument, None)
+            };
+
+            if query.is_empty() {
+                send_message(bot, chat_id, "Usage: /mute &lt;sensor&gt; [duration], for instance /mute door 2h").await;
+                return;
+            }
+
+            let until = match duration.map(Timestamp::in_seconds) {
+                Some(None) => {
+                    send_message(bot, chat_id, "Duration too long").await;
+                    return;
+                },
+                until => until.flatten()
+            };
+
+            let sensor_name = match find_sensor_name(bot, chat_id, &locked_shared_data.prev_sensors_data, query).await {
+                Some(sensor_name) => sensor_name,
+                None => return
+            };
+
+            for prev_sensor_data in locked_shared_data.prev_sensors_data.sensors_named(&sensor_name) {
+                prev_sensor_data.mute = Some(Mute { until: until.clone() });
+            }
+
+            let message = match duration {
+                Some(duration) => format!("Sensor <b>{}</b> muted for {}", escape_html(&sensor_name), format_dhms(duration)),
+                None => format!("Sensor <b>{}</b> muted until /unmute", escape_html(&sensor_name))
+            };
+            log::info!("sensor {} muted from chat {} for {:?} seconds", sensor_name, chat_id, duration);
+            send_message(bot, chat_id, &message).await;
+        },
+
+        "/unmute" => {
+            if argument.is_empty() {
+                send_message(bot, chat_id, "Usage: /unmute &lt;sensor&gt;").await;
+                return;
+            }
+
+            let sensor_name = match find_sensor_name(bot, chat_id, &locked_shared_data.prev_sensors_data, argument).await {
+                Some(sensor_name) => sensor_name,
+                None => return
+            };
+
+            let mut was_muted = false;
+            for prev_sensor_data in locked_shared_data.prev_sensors_data.sensors_named(&sensor_name) {
+                was_muted |= prev_sensor_data.is_muted();
+                prev_sensor_data.mute = None;
+            }
+
+            let message = if was_muted {
+                log::info!("sensor {} unmuted from chat {}", sensor_name, chat_id);
+                format!("Sensor <b>{}</b> unmuted", escape_html(&sensor_name))
+            } else {
+                format!("Sensor <b>{}</b> is not muted", escape_html(&sensor_name))
+            };
+            send_message(bot, chat_id, &message).await;
+        },
+
+        "/muted" => {
+            let mut muted_info = locked_shared_data.prev_sensors_data.values().filter(|prev_sensor_data| prev_sensor_data.is_muted()).filter_map(|prev_sensor_data| {
+                let until = prev_sensor_data.mute.as_ref()?.until.as_ref();
+                Some(match until {
+                    Some(until) => format!("• <b>{}</b>: until {} ({} left)", escape_html(&prev_sensor_data.name), until.format("%Y-%m-%d %H:%M:%S"), until.time_left()),
+                    None => format!("• <b>{}</b>: until /unmute", escape_html(&prev_sensor_data.name))
+                })
+            }).collect::<Vec<String>>();
+            muted_info.sort();
+            muted_info.dedup();
+
+            let message = if muted_info.is_empty() { "No muted sensors".to_owned() } else { format!("Muted sensors:\n{}", muted_info.join("\n")) };
+            send_message(bot, chat_id, &message).await;
+        },
+
         "/status" => {
-            let sensors_info = locked_shared_data.prev_sensors_data.iter().map(|(_mqtt_topic, prev_sensor_data)| {
-                format!("• <b>{}</b>: last seen {} ago", prev_sensor_data.name, prev_sensor_data.time_since_last_seen())
+            let sensors_info = locked_shared_data.prev_sensors_data.values().map(|prev_sensor_data| {
+                format!("• <b>{}</b>: last seen {} ago", escape_html(&prev_sensor_data.name), prev_sensor_data.time_since_last_seen())
             }).collect::<Vec<String>>();
 
             let sensors_info_str = if sensors_info.is_empty() { "no sensors seen".to_owned() } else { sensors_info.join("\n") };
@@ -134,14 +404,46 @@ async fn handle_commands(bot: &AutoSend<Bot>, chat_id: &ChatId, command: &str, s
                 true => "enabled",
                 false => "disabled",
             };
-            send_message(bot, chat_id, format!("Sensors:\n{}\n\nNotifications are {}", sensors_info_str, notifications_status_str).as_str()).await;
+            let arming_mode_str = match config.uses_arming_modes() {
+                true => format!("\nAlarm mode: <b>{}</b>", escape_html(&locked_shared_data.arming_mode)),
+                false => String::new()
+            };
+            let schedule_str = match schedule::next_change(config) {
+                Some((occurrence, _)) => format!("\nNext scheduled change: {}", occurrence.format("%a %H:%M")),
+                None => String::new()
+            };
+            let alerts_str = match locked_shared_data.alerts.len() {
+                0 => String::new(),
+                pending_alerts => format!("\n{pending_alerts} alert(s) waiting for acknowledgement")
+            };
+            let outbox_str = match locked_shared_data.outbox.len() {
+                0 => String::new(),
+                queued_notifications => format!("\n{queued_notifications} notification(s) waiting to be sent")
+            };
+            send_message(bot, chat_id, format!("Sensors:\n{}\n\nNotifications are {}{}{}{}{}", sensors_info_str, notifications_status_str, arming_mode_str, schedule_str, alerts_str, outbox_str).as_str()).await;
+        },
+
+        "/reload" => {
+            if config.telegram.is_admin_chat_id(chat_id) {
+                if reload_sender.send("/reload command").is_err() {
+                    send_message(bot, chat_id, "Failed to request configuration reload").await;
+                }
+            } else {
+                send_message(bot, chat_id, "The configuration can only be reloaded from admin chats").await;
+            }
         },
 
         "/help" => {
             send_message(bot, chat_id, "/enable - enable notifications\n\
                                         /disable - disable notifications\n\
+                                        /arm &lt;mode&gt; - set the alarm mode\n\
+                                        /mute &lt;sensor&gt; [duration] - mute the messages of a sensor\n\
+                                        /unmute &lt;sensor&gt; - unmute a sensor\n\
+                                        /muted - list the muted sensors\n\
+                                        /disarm - disarm the alarm\n\
                                         /status - display bot and sensors status\n\
-                                        /battery - display latest sensors battery info").await;
+                                        /battery - display latest sensors battery info\n\
+                                        /reload - reload the configuration file (admin chats only)").await;
         }
 
         _ => send_message(bot, chat_id, "Invalid command, use /help to display available commands").await
@@ -156,7 +458,7 @@ pub async fn start_chat_id_discovery(config: &config::Telegram) {
 
             let chat_name = if message.chat.is_private() {
                 let (first_name, last_name) = (message.chat.first_name(), message.chat.last_name());
-                first_name.into_iter().chain(last_name.into_iter()).collect::<Vec<&str>>().join(" ")
+                first_name.into_iter().chain(last_name).collect::<Vec<&str>>().join(" ")
             } else {
                 message.chat.title().unwrap_or_default().to_owned()
             };
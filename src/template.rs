@@ -0,0 +1,280 @@
+use std::str::FromStr;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::rules;
+use crate::telegram::escape_html;
+
+/// Variables available in every message template, besides the `payload.<field>` ones and the named captures
+pub const VARIABLES: &[&str] = &["sensor", "topic", "field", "value", "previous", "timestamp", "battery", "voltage", "last_seen"];
+
+pub const PAYLOAD_VARIABLE_PREFIX: &str = "payload.";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    /// `round` or `round(2)`: number of decimals, 0 by default
+    Round(usize),
+    /// `unit(°C)`: appends the unit to the value
+    Unit(String),
+    Upper,
+    Lower,
+    /// `default(unknown)`: used when the variable has no value
+    Default(String),
+    /// disables the HTML escaping of the value
+    Raw
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Placeholder { variable: String, filters: Vec<Filter> }
+}
+
+/// Message template with `{variable | filter | filter(argument)}` placeholders, `{{` and `}}` are literal braces.
+/// The substituted values are HTML escaped unless the `raw` filter is used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TemplateParseError {
+    #[error("unclosed placeholder in template {0:?}")]
+    UnclosedPlaceholder(String),
+    #[error("unmatched closing brace in template {0:?}, use }}}} for a literal brace")]
+    UnmatchedClosingBrace(String),
+    #[error("invalid variable name {0:?}")]
+    InvalidVariableName(String),
+    #[error("unknown filter {0:?}")]
+    UnknownFilter(String),
+    #[error("invalid argument for filter {0:?}: {1:?}")]
+    InvalidFilterArgument(String, String)
+}
+
+fn parse_filter(filter_str: &str) -> Result<Filter, TemplateParseError> {
+    let (name, argument) = match filter_str.split_once('(') {
+        Some((name, argument)) => match argument.strip_suffix(')') {
+            Some(argument) => (name.trim(), Some(argument)),
+            None => return Err(TemplateParseError::InvalidFilterArgument(name.trim().to_owned(), argument.to_owned()))
+        },
+        None => (filter_str, None)
+    };
+
+    let invalid_argument = || TemplateParseError::InvalidFilterArgument(name.to_owned(), argument.unwrap_or_default().to_owned());
+
+    match (name, argument) {
+        ("round", None) => Ok(Filter::Round(0)),
+        ("round", Some(decimals)) => decimals.trim().parse().map(Filter::Round).map_err(|_| invalid_argument()),
+        ("unit", Some(unit)) => Ok(Filter::Unit(unit.to_owned())),
+        ("upper", None) => Ok(Filter::Upper),
+        ("lower", None) => Ok(Filter::Lower),
+        ("default", Some(default)) => Ok(Filter::Default(default.to_owned())),
+        ("raw", None) => Ok(Filter::Raw),
+        ("unit" | "upper" | "lower" | "default" | "raw", _) => Err(invalid_argument()),
+        _ => Err(TemplateParseError::UnknownFilter(name.to_owned()))
+    }
+}
+
+fn parse_placeholder(placeholder_str: &str) -> Result<Segment, TemplateParseError> {
+    let mut parts = placeholder_str.split('|').map(str::trim);
+    let variable = parts.next().unwrap_or_default();
+
+    if variable.is_empty() || !variable.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '/') {
+        return Err(TemplateParseError::InvalidVariableName(variable.to_owned()));
+    }
+
+    let filters = parts.map(parse_filter).collect::<Result<Vec<Filter>, TemplateParseError>>()?;
+
+    Ok(Segment::Placeholder { variable: variable.to_owned(), filters })
+}
+
+fn value_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(string) => string.clone(),
+        serde_json::Value::Null => String::new(),
+        value => value.to_string()
+    }
+}
+
+impl Template {
+
+    /// Names of the variables referenced by the placeholders
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Placeholder { variable, .. } => Some(variable.as_str()),
+            Segment::Text(_) => None
+        })
+    }
+
+    /// Renders the template, `variables` returns the value of a variable or None when it has no value
+    pub fn render<F: Fn(&str) -> Option<serde_json::Value>>(&self, variables: F) -> String {
+        let mut message = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => message.push_str(text),
+                Segment::Placeholder { variable, filters } => {
+                    let mut value = variables(variable);
+                    let mut escape = true;
+
+                    for filter in filters {
+                        value = match (filter, value) {
+                            (Filter::Round(decimals), Some(value)) => Some(match rules::numeric_value(&value) {
+                                Some(number) => serde_json::Value::String(format!("{number:.decimals$}")),
+                                None => value
+                            }),
+                            (Filter::Unit(unit), Some(value)) => Some(serde_json::Value::String(value_text(&value) + unit)),
+                            (Filter::Upper, Some(value)) => Some(serde_json::Value::String(value_text(&value).to_uppercase())),
+                            (Filter::Lower, Some(value)) => Some(serde_json::Value::String(value_text(&value).to_lowercase())),
+                            (Filter::Default(default), None) => Some(serde_json::Value::String(default.clone())),
+                            (Filter::Raw, value) => {
+                                escape = false;
+                                value
+                            },
+                            (_, value) => value
+                        };
+                    }
+
+                    let text = value.as_ref().map(value_text).unwrap_or_default();
+                    message.push_str(if escape { escape_html(&text) } else { text }.as_str());
+                }
+            }
+        }
+
+        message
+    }
+
+}
+
+impl FromStr for Template {
+    type Err = TemplateParseError;
+
+    fn from_str(template_str: &str) -> Result<Self, Self::Err> {
+        let mut segments = vec![];
+        let mut text = String::new();
+        let mut chars = template_str.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '}' => return Err(TemplateParseError::UnmatchedClosingBrace(template_str.to_owned())),
+                '{' => {
+                    let mut placeholder_str = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder_str.push(c),
+                            None => return Err(TemplateParseError::UnclosedPlaceholder(template_str.to_owned()))
+                        }
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(parse_placeholder(&placeholder_str)?);
+                },
+                c => text.push(c)
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let template_str = String::deserialize(deserializer)?;
+        template_str.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template_str: &str, variables: &[(&str, serde_json::Value)]) -> String {
+        let template: Template = template_str.parse().unwrap();
+        template.render(|name| variables.iter().find(|(variable, _)| *variable == name).map(|(_, value)| value.clone()))
+    }
+
+    #[test]
+    fn parse_placeholders() {
+        let template: Template = "{sensor} is {value | round(1) | unit(°C)}".parse().unwrap();
+        assert_eq!(template.segments, vec![
+            Segment::Placeholder { variable: "sensor".to_owned(), filters: vec![] },
+            Segment::Text(" is ".to_owned()),
+            Segment::Placeholder { variable: "value".to_owned(), filters: vec![Filter::Round(1), Filter::Unit("°C".to_owned())] }
+        ]);
+        assert_eq!(template.variables().collect::<Vec<&str>>(), vec!["sensor", "value"]);
+    }
+
+    #[test]
+    fn parse_escaped_braces() {
+        let template: Template = "{{literal}} {{{value}}}".parse().unwrap();
+        assert_eq!(template.segments, vec![
+            Segment::Text("{literal} {".to_owned()),
+            Segment::Placeholder { variable: "value".to_owned(), filters: vec![] },
+            Segment::Text("}".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!("open {value".parse::<Template>(), Err(TemplateParseError::UnclosedPlaceholder("open {value".to_owned())));
+        assert_eq!("close }".parse::<Template>(), Err(TemplateParseError::UnmatchedClosingBrace("close }".to_owned())));
+        assert_eq!("{value} }".parse::<Template>(), Err(TemplateParseError::UnmatchedClosingBrace("{value} }".to_owned())));
+        assert_eq!("{}".parse::<Template>(), Err(TemplateParseError::InvalidVariableName(String::new())));
+        assert_eq!("{a b}".parse::<Template>(), Err(TemplateParseError::InvalidVariableName("a b".to_owned())));
+        assert_eq!("{value | bold}".parse::<Template>(), Err(TemplateParseError::UnknownFilter("bold".to_owned())));
+        assert_eq!("{value | round(x)}".parse::<Template>(), Err(TemplateParseError::InvalidFilterArgument("round".to_owned(), "x".to_owned())));
+        assert_eq!("{value | unit}".parse::<Template>(), Err(TemplateParseError::InvalidFilterArgument("unit".to_owned(), String::new())));
+        assert_eq!("{value | upper(1)}".parse::<Template>(), Err(TemplateParseError::InvalidFilterArgument("upper".to_owned(), "1".to_owned())));
+    }
+
+    #[test]
+    fn render_escaped_braces() {
+        assert_eq!(render("{{{value}}}", &[("value", serde_json::json!(3))]), "{3}");
+        assert_eq!(render("}} and {{", &[]), "} and {");
+    }
+
+    #[test]
+    fn render_values() {
+        let variables = [("sensor", serde_json::json!("Door")), ("value", serde_json::json!(21.456)), ("state", serde_json::json!(true))];
+        assert_eq!(render("{sensor}: {value}", &variables), "Door: 21.456");
+        assert_eq!(render("{state}", &variables), "true");
+        // a variable without value renders as an empty string
+        assert_eq!(render("[{missing}]", &variables), "[]");
+    }
+
+    #[test]
+    fn render_filters() {
+        let variables = [("sensor", serde_json::json!("Door")), ("value", serde_json::json!(21.456)), ("text", serde_json::json!("on"))];
+        assert_eq!(render("{value | round}", &variables), "21");
+        assert_eq!(render("{value | round(2) | unit(°C)}", &variables), "21.46°C");
+        assert_eq!(render("{text | round(2)}", &variables), "on");
+        assert_eq!(render("{sensor | upper} {sensor | lower}", &variables), "DOOR door");
+        assert_eq!(render("{missing | default(unknown)} {sensor | default(unknown)}", &variables), "unknown Door");
+        assert_eq!(render("{missing | unit(%)}", &variables), "");
+    }
+
+    #[test]
+    fn render_escapes_html() {
+        let variables = [("value", serde_json::json!("<b>a & b</b>"))];
+        assert_eq!(render("<i>{value}</i>", &variables), "<i>&lt;b&gt;a &amp; b&lt;/b&gt;</i>");
+        assert_eq!(render("{value | raw}", &variables), "<b>a & b</b>");
+    }
+
+}
@@ -5,7 +5,7 @@ use std::ops::Deref;
 
 type TimestampInner = chrono::DateTime<chrono::Local>;
 
-#[derive(Serialize,Deserialize)]
+#[derive(Serialize,Deserialize,Debug,Clone)]
 pub struct Timestamp(TimestampInner);
 
 impl Deref for Timestamp {
@@ -20,6 +20,51 @@ impl Timestamp {
     pub fn now() -> Self {
         Self(chrono::Local::now())
     }
+
+    /// returns: None when the timestamp is out of the supported range
+    pub fn in_seconds(seconds: u64) -> Option<Self> {
+        let duration = Duration::from_std(std::time::Duration::from_secs(seconds)).ok()?;
+        chrono::Local::now().checked_add_signed(duration).map(Self)
+    }
+
+    pub fn is_past(&self) -> bool {
+        self.0 <= chrono::Local::now()
+    }
+
+    /// Time left until the timestamp, zero if it is past
+    pub fn time_left(&self) -> String {
+        format_dhms(self.0.signed_duration_since(chrono::Local::now()).num_seconds().max(0))
+    }
+}
+
+/// Parses durations such as `90s`, `30m`, `2h`, `1d` or `1h30m`, the unit is required so that a number ending a sensor name
+/// is not taken for a duration
+/// returns: the duration in seconds
+pub fn parse_duration(duration_str: &str) -> Option<u64> {
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in duration_str.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit_seconds = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None
+        };
+        let value: u64 = std::mem::take(&mut number).parse().ok()?;
+        seconds = seconds.checked_add(value.checked_mul(unit_seconds)?)?;
+    }
+
+    if !number.is_empty() || seconds == 0 {
+        return None;
+    }
+
+    Some(seconds)
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -44,3 +89,44 @@ impl Deref for LastSeenDuration {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("30m"), Some(1800));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("1w"), Some(604800));
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+    }
+
+    #[test]
+    fn parse_duration_requires_unit() {
+        assert_eq!(parse_duration("2"), None);
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("2x"), None);
+    }
+
+    #[test]
+    fn parse_duration_overflow() {
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+        assert_eq!(parse_duration("40000000000000w"), None);
+        assert_eq!(parse_duration("18446744073709551615s1s"), None);
+    }
+
+    #[test]
+    fn timestamp_out_of_range() {
+        let seconds = parse_duration("99999999w").unwrap();
+        assert!(Timestamp::in_seconds(seconds).is_none());
+        assert!(Timestamp::in_seconds(u64::MAX).is_none());
+        assert!(Timestamp::in_seconds(3600).is_some_and(|timestamp| !timestamp.is_past()));
+    }
+
+}
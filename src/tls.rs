@@ -0,0 +1,129 @@
+use std::{path::Path, sync::Arc, time::SystemTime};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls_pemfile::Item;
+use thiserror::Error;
+
+use crate::config;
+
+#[derive(Debug, Error)]
+pub enum TlsConfigError {
+    #[error("failed to read {0}: {1}")]
+    IOError(String, std::io::Error),
+    #[error("no certificate found in {0}")]
+    NoCertificate(String),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+    #[error("invalid CA certificate in {0}")]
+    InvalidCaCertificate(String),
+    #[error("TLS error: {0}")]
+    RustlsError(rustls::Error)
+}
+
+/// Accepts any server certificate, only meant to be used for lab setups
+struct NoServerCertVerification;
+
+impl ServerCertVerifier for NoServerCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn read_pem_file<S: AsRef<Path>>(file_path: S) -> Result<Vec<Item>, TlsConfigError> {
+    let path_str = file_path.as_ref().display().to_string();
+    let file = std::fs::File::open(&file_path).map_err(|error| TlsConfigError::IOError(path_str.clone(), error))?;
+    let mut reader = std::io::BufReader::new(file);
+    rustls_pemfile::read_all(&mut reader).map_err(|error| TlsConfigError::IOError(path_str, error))
+}
+
+fn load_certificates(file_path: &str) -> Result<Vec<Certificate>, TlsConfigError> {
+    let certificates: Vec<Certificate> = read_pem_file(file_path)?.into_iter().filter_map(|item| match item {
+        Item::X509Certificate(der) => Some(Certificate(der)),
+        _ => None
+    }).collect();
+
+    if certificates.is_empty() {
+        return Err(TlsConfigError::NoCertificate(file_path.to_owned()));
+    }
+
+    Ok(certificates)
+}
+
+fn load_private_key(file_path: &str) -> Result<PrivateKey, TlsConfigError> {
+    read_pem_file(file_path)?.into_iter().find_map(|item| match item {
+        Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+        _ => None
+    }).ok_or_else(|| TlsConfigError::NoPrivateKey(file_path.to_owned()))
+}
+
+/// Builds the rustls client configuration from the mqtt_broker TLS settings, loading all the referenced files
+pub fn load_client_config(tls: &config::MqttTls) -> Result<ClientConfig, TlsConfigError> {
+    let mut root_cert_store = RootCertStore::empty();
+
+    if let Some(ca_file) = &tls.ca_file {
+        let ca_certificates: Vec<Vec<u8>> = load_certificates(ca_file)?.into_iter().map(|certificate| certificate.0).collect();
+        let (_, invalid_count) = root_cert_store.add_parsable_certificates(&ca_certificates);
+        if invalid_count > 0 {
+            return Err(TlsConfigError::InvalidCaCertificate(ca_file.clone()));
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_cert_store);
+
+    let mut client_config = match (&tls.client_cert_file, &tls.client_key_file) {
+        (Some(client_cert_file), Some(client_key_file)) => {
+            let client_certificates = load_certificates(client_cert_file)?;
+            let client_key = load_private_key(client_key_file)?;
+            builder.with_single_cert(client_certificates, client_key).map_err(TlsConfigError::RustlsError)?
+        },
+        _ => builder.with_no_client_auth()
+    };
+
+    if tls.insecure_skip_verify {
+        client_config.dangerous().set_certificate_verifier(Arc::new(NoServerCertVerification));
+    }
+
+    Ok(client_config)
+}
+
+/// Builds a rustls client configuration trusting the system certificates and those of the optional CA bundle
+pub fn load_system_client_config(ca_file: Option<&str>, insecure_skip_verify: bool) -> Result<ClientConfig, TlsConfigError> {
+    let mut root_cert_store = RootCertStore::empty();
+
+    match rustls_native_certs::load_native_certs() {
+        Ok(native_certificates) => {
+            let native_certificates: Vec<Vec<u8>> = native_certificates.into_iter().map(|certificate| certificate.0).collect();
+            root_cert_store.add_parsable_certificates(&native_certificates);
+        },
+        Err(load_error) => log::warn!("failed to load the system certificates: {}", load_error)
+    }
+
+    if let Some(ca_file) = ca_file {
+        let ca_certificates: Vec<Vec<u8>> = load_certificates(ca_file)?.into_iter().map(|certificate| certificate.0).collect();
+        let (_, invalid_count) = root_cert_store.add_parsable_certificates(&ca_certificates);
+        if invalid_count > 0 {
+            return Err(TlsConfigError::InvalidCaCertificate(ca_file.to_owned()));
+        }
+    }
+
+    let mut client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+
+    if insecure_skip_verify {
+        client_config.dangerous().set_certificate_verifier(Arc::new(NoServerCertVerification));
+    }
+
+    Ok(client_config)
+}
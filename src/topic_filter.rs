@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Level {
+    Literal(String),
+    /// `+`, optionally named: `+room`
+    SingleWildcard(Option<String>),
+    /// `#`, optionally named: `#rest`, only allowed as the last level
+    MultiWildcard(Option<String>)
+}
+
+/// MQTT topic filter whose wildcards can be named so that the topic levels they match can be used in message templates.
+///
+/// The sensor name is made of the levels matched by the unnamed wildcards joined with `/`, or is the whole topic
+/// if all the wildcards are named. A filter without any wildcard is a topic base: `zigbee2mqtt` is the same as `zigbee2mqtt/+`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicFilter {
+    filter_str: String,
+    levels: Vec<Level>
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TopicFilterParseError {
+    #[error("empty topic filter")]
+    Empty,
+    #[error("invalid wildcard level {0:?} in topic filter {1:?}")]
+    InvalidWildcard(String, String),
+    #[error("multi-level wildcard must be the last level in topic filter {0:?}")]
+    MultiLevelNotLast(String),
+    #[error("duplicate wildcard name {0:?} in topic filter {1:?}")]
+    DuplicateName(String, String)
+}
+
+pub type TopicCaptures = HashMap<String, String>;
+
+pub struct TopicMatch {
+    pub sensor_name: String,
+    pub captures: TopicCaptures
+}
+
+fn wildcard_name(level_str: &str, filter_str: &str) -> Result<Option<String>, TopicFilterParseError> {
+    let name = &level_str[1..];
+    if name.is_empty() {
+        Ok(None)
+    } else if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(Some(name.to_owned()))
+    } else {
+        Err(TopicFilterParseError::InvalidWildcard(level_str.to_owned(), filter_str.to_owned()))
+    }
+}
+
+impl TopicFilter {
+
+    /// The filter used to subscribe to the broker, without the wildcard names
+    pub fn subscribe_pattern(&self) -> String {
+        self.levels.iter().map(|level| match level {
+            Level::Literal(literal) => literal.as_str(),
+            Level::SingleWildcard(_) => "+",
+            Level::MultiWildcard(_) => "#"
+        }).collect::<Vec<&str>>().join("/")
+    }
+
+    /// Names of the named wildcards, available in the message templates
+    pub fn wildcard_names(&self) -> impl Iterator<Item = &str> {
+        self.levels.iter().filter_map(|level| match level {
+            Level::SingleWildcard(Some(name)) | Level::MultiWildcard(Some(name)) => Some(name.as_str()),
+            _ => None
+        })
+    }
+
+    pub fn match_topic(&self, topic: &str) -> Option<TopicMatch> {
+        let topic_levels: Vec<&str> = topic.split('/').collect();
+        let mut sensor_name_levels: Vec<&str> = vec![];
+        let mut captures = TopicCaptures::new();
+
+        for (index, level) in self.levels.iter().enumerate() {
+            match level {
+                Level::Literal(literal) => if topic_levels.get(index) != Some(&literal.as_str()) {
+                    return None;
+                },
+                Level::SingleWildcard(name) => {
+                    let topic_level = *topic_levels.get(index)?;
+                    match name {
+                        Some(name) => { captures.insert(name.clone(), topic_level.to_owned()); },
+                        None => sensor_name_levels.push(topic_level)
+                    }
+                },
+                Level::MultiWildcard(name) => {
+                    let remaining_levels = topic_levels.get(index..).unwrap_or_default();
+                    match name {
+                        Some(name) => { captures.insert(name.clone(), remaining_levels.join("/")); },
+                        None => sensor_name_levels.extend(remaining_levels)
+                    }
+                    return Some(self.topic_match(topic, sensor_name_levels, captures));
+                }
+            }
+        }
+
+        if topic_levels.len() != self.levels.len() {
+            return None;
+        }
+
+        Some(self.topic_match(topic, sensor_name_levels, captures))
+    }
+
+    fn topic_match(&self, topic: &str, sensor_name_levels: Vec<&str>, captures: TopicCaptures) -> TopicMatch {
+        let has_unnamed_wildcard = self.levels.iter().any(|level| matches!(level, Level::SingleWildcard(None) | Level::MultiWildcard(None)));
+        let sensor_name = if has_unnamed_wildcard { sensor_name_levels.join("/") } else { topic.to_owned() };
+        TopicMatch { sensor_name, captures }
+    }
+
+}
+
+impl FromStr for TopicFilter {
+    type Err = TopicFilterParseError;
+
+    fn from_str(filter_str: &str) -> Result<Self, Self::Err> {
+        if filter_str.is_empty() {
+            return Err(TopicFilterParseError::Empty);
+        }
+
+        let level_strs: Vec<&str> = filter_str.split('/').collect();
+        let mut levels = Vec::with_capacity(level_strs.len() + 1);
+        let mut names: Vec<&str> = vec![];
+
+        for (index, level_str) in level_strs.iter().enumerate() {
+            let level = if level_str.starts_with('+') {
+                Level::SingleWildcard(wildcard_name(level_str, filter_str)?)
+            } else if level_str.starts_with('#') {
+                if index != level_strs.len() - 1 {
+                    return Err(TopicFilterParseError::MultiLevelNotLast(filter_str.to_owned()));
+                }
+                Level::MultiWildcard(wildcard_name(level_str, filter_str)?)
+            } else if level_str.contains(['+', '#']) {
+                return Err(TopicFilterParseError::InvalidWildcard(level_str.to_string(), filter_str.to_owned()));
+            } else {
+                Level::Literal(level_str.to_string())
+            };
+
+            if let Level::SingleWildcard(Some(name)) | Level::MultiWildcard(Some(name)) = &level {
+                if names.contains(&name.as_str()) {
+                    return Err(TopicFilterParseError::DuplicateName(name.clone(), filter_str.to_owned()));
+                }
+                names.push(&level_str[1..]);
+            }
+
+            levels.push(level);
+        }
+
+        // topic base
+        if levels.iter().all(|level| matches!(level, Level::Literal(_))) {
+            levels.push(Level::SingleWildcard(None));
+        }
+
+        Ok(Self { filter_str: filter_str.to_owned(), levels })
+    }
+}
+
+impl std::fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.filter_str)
+    }
+}
+
+impl<'de> Deserialize<'de> for TopicFilter {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let filter_str = String::deserialize(deserializer)?;
+        filter_str.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(filter_str: &str) -> TopicFilter {
+        filter_str.parse().unwrap()
+    }
+
+    fn sensor_name(filter_str: &str, topic: &str) -> Option<String> {
+        filter(filter_str).match_topic(topic).map(|topic_match| topic_match.sensor_name)
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!("".parse::<TopicFilter>(), Err(TopicFilterParseError::Empty));
+        assert_eq!("home/#/temp".parse::<TopicFilter>(), Err(TopicFilterParseError::MultiLevelNotLast("home/#/temp".to_owned())));
+        assert_eq!("home/a+b".parse::<TopicFilter>(), Err(TopicFilterParseError::InvalidWildcard("a+b".to_owned(), "home/a+b".to_owned())));
+        assert_eq!("home/+room-1".parse::<TopicFilter>(), Err(TopicFilterParseError::InvalidWildcard("+room-1".to_owned(), "home/+room-1".to_owned())));
+        assert_eq!("+room/+room".parse::<TopicFilter>(), Err(TopicFilterParseError::DuplicateName("room".to_owned(), "+room/+room".to_owned())));
+    }
+
+    #[test]
+    fn subscribe_pattern() {
+        assert_eq!(filter("zigbee2mqtt").subscribe_pattern(), "zigbee2mqtt/+");
+        assert_eq!(filter("home/+room/#rest").subscribe_pattern(), "home/+/#");
+        assert_eq!(filter("home/+room/#rest").wildcard_names().collect::<Vec<&str>>(), vec!["room", "rest"]);
+        assert_eq!(filter("home/+room/#rest").to_string(), "home/+room/#rest");
+    }
+
+    #[test]
+    fn topic_base() {
+        assert_eq!(sensor_name("zigbee2mqtt", "zigbee2mqtt/door"), Some("door".to_owned()));
+        assert_eq!(sensor_name("zigbee2mqtt", "zigbee2mqtt"), None);
+        assert_eq!(sensor_name("zigbee2mqtt", "zigbee2mqtt/door/availability"), None);
+        assert_eq!(sensor_name("zigbee2mqtt", "other/door"), None);
+    }
+
+    #[test]
+    fn single_level_wildcard() {
+        assert_eq!(sensor_name("home/+/state", "home/kitchen/state"), Some("kitchen".to_owned()));
+        assert_eq!(sensor_name("home/+/state", "home/kitchen/temp"), None);
+        assert_eq!(sensor_name("home/+/state", "home/kitchen"), None);
+        assert_eq!(sensor_name("home/+/state", "home/kitchen/state/extra"), None);
+        assert_eq!(sensor_name("+/+", "home/kitchen"), Some("home/kitchen".to_owned()));
+    }
+
+    #[test]
+    fn multi_level_wildcard() {
+        assert_eq!(sensor_name("home/#", "home/kitchen/motion"), Some("kitchen/motion".to_owned()));
+        assert_eq!(sensor_name("home/#", "home/kitchen"), Some("kitchen".to_owned()));
+        // as in MQTT, # also matches its parent level
+        assert_eq!(sensor_name("home/#", "home"), Some(String::new()));
+        assert_eq!(sensor_name("home/#", "office/kitchen"), None);
+        assert_eq!(sensor_name("home/+/#", "home/kitchen/motion/1"), Some("kitchen/motion/1".to_owned()));
+    }
+
+    #[test]
+    fn named_wildcards() {
+        let topic_match = filter("home/+room/#device").match_topic("home/kitchen/motion/1").unwrap();
+        // all the wildcards are named, the sensor name is the whole topic
+        assert_eq!(topic_match.sensor_name, "home/kitchen/motion/1");
+        assert_eq!(topic_match.captures.get("room").map(String::as_str), Some("kitchen"));
+        assert_eq!(topic_match.captures.get("device").map(String::as_str), Some("motion/1"));
+
+        let topic_match = filter("home/+room/+").match_topic("home/kitchen/motion").unwrap();
+        assert_eq!(topic_match.sensor_name, "motion");
+        assert_eq!(topic_match.captures.get("room").map(String::as_str), Some("kitchen"));
+    }
+
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::sensors::{Mute, PrevData};
+use crate::time::{LastSeenDuration, Timestamp};
+use crate::notifier;
+use crate::{ProtectedSharedState, SharedConfig, telegram};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// returns: messages for the fields which stopped flapping, their value did not change during the whole window
+fn check_flapping(config: &Config, prev_sensor_data: &mut PrevData) -> Vec<String> {
+    let flap_detection = match &config.flap_detection {
+        Some(flap_detection) => flap_detection,
+        None => {
+            prev_sensor_data.flap_states.clear();
+            return vec![];
+        }
+    };
+
+    let mut messages = vec![];
+
+    for (field_name, flap_state) in prev_sensor_data.flap_states.iter_mut() {
+        if flap_state.check_stable(flap_detection.window) {
+            log::info!("field {} of sensor {} stopped flapping", field_name, prev_sensor_data.name);
+            let value = prev_sensor_data.trigger_states.get(field_name).map(|value| match value {
+                serde_json::Value::String(value_str) => value_str.clone(),
+                value => value.to_string()
+            }).unwrap_or_default();
+            messages.push(format!("Sensor <b>{}</b> stopped flapping: {} is {}",
+                telegram::escape_html(&prev_sensor_data.name),
+                telegram::escape_html(field_name),
+                telegram::escape_html(&value)
+            ));
+        }
+    }
+
+    prev_sensor_data.flap_states.retain(|_, flap_state| flap_state.flapping || flap_state.transitions_count() > 0);
+
+    if prev_sensor_data.is_muted() {
+        messages.clear();
+    }
+
+    messages
+}
+
+// the silence is counted from the bot start at most, the sensors could not be heard while it was stopped
+async fn check(config: &Config, shared_state: &ProtectedSharedState, started_at: &Timestamp) {
+    let mut messages = vec![];
+    let mut locked_shared_state = shared_state.lock().await;
+
+    for (topic, prev_sensor_data) in locked_shared_state.prev_sensors_data.iter_mut() {
+        let mut sensor_messages = vec![];
+
+        if prev_sensor_data.mute.as_ref().is_some_and(Mute::is_expired) {
+            log::info!("sensor {} mute expired", prev_sensor_data.name);
+            prev_sensor_data.mute = None;
+        }
+
+        sensor_messages.extend(check_flapping(config, prev_sensor_data));
+
+        if let (false, Some(max_silence)) = (prev_sensor_data.offline, config.max_silence.match_sensor_name(&prev_sensor_data.name)) {
+            let time_since_last_seen = prev_sensor_data.time_since_last_seen();
+            let silence = std::cmp::min(prev_sensor_data.time_since_last_seen(), LastSeenDuration::new(started_at));
+            if silence.num_seconds() >= max_silence as i64 {
+                log::info!("sensor {} offline, last seen {} ago", prev_sensor_data.name, time_since_last_seen);
+                prev_sensor_data.offline = true;
+                if !prev_sensor_data.is_muted() {
+                    sensor_messages.push(format!("Sensor <b>{}</b> offline since {} ({} ago)",
+                        telegram::escape_html(&prev_sensor_data.name),
+                        prev_sensor_data.update_timestamp.format("%Y-%m-%d %H:%M:%S"),
+                        time_since_last_seen
+                    ));
+                }
+            }
+        }
+
+        // sent to the recipients of the sensor, like its back online message
+        if !sensor_messages.is_empty() {
+            let destinations = config.destinations(config.mqtt_topics.sensor_recipients(topic), None);
+            messages.extend(sensor_messages.into_iter().map(|message| (message, destinations.clone())));
+        }
+    }
+
+    drop(locked_shared_state);
+
+    for (message, destinations) in messages {
+        notifier::notify(config, shared_state, message, &destinations, None).await;
+    }
+}
+
+/// Periodically checks the time since each tracked sensor has been seen against its configured max silence
+pub async fn run(shared_config: SharedConfig, shared_state: ProtectedSharedState) {
+    let started_at = Timestamp::now();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let config = shared_config.read().await.clone();
+        check(&config, &shared_state, &started_at).await;
+    }
+}